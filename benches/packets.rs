@@ -0,0 +1,169 @@
+//! Times tracing the meshes of a scene through trees over single triangles,
+//! and through the same trees with the triangles of each leaf packed together, as meshes are.
+//!
+//! Every mesh in a scene is traced with a ray through the center of each pixel, once for
+//! the closest hit and once for occlusion, with both a kd-tree and a BVH. Meshes that
+//! can't be loaded, like the ones missing from `examples/meshes`, are skipped.
+//!
+//! Usage: `cargo bench [-- SCENE...]`, using the example scenes by default.
+
+use std::error::Error;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use prayer::camera::CameraConfig;
+use prayer::geom::{AccelType, Geometry, KdTreeConfig, Mesh};
+use prayer::obj;
+use prayer::ray::Ray;
+
+/// Number of times each path is timed, keeping the fastest run
+const RUNS: usize = 5;
+
+/// The parts of a scene file the benchmark needs.
+#[derive(Deserialize)]
+struct BenchConfig {
+    params: Params,
+    #[serde(default)]
+    camera: CameraConfig,
+    scene: SceneDesc,
+}
+
+#[derive(Deserialize)]
+struct Params {
+    resolution: [u32; 2],
+}
+
+#[derive(Deserialize)]
+struct SceneDesc {
+    objects: Vec<ObjectDesc>,
+    #[serde(default)]
+    meshes: toml::value::Table,
+    #[serde(default)]
+    kd_tree: KdTreeConfig,
+}
+
+#[derive(Deserialize)]
+struct ObjectDesc {
+    geometry: toml::Value,
+}
+
+fn main() {
+    // Cargo passes `--bench` to benchmarks run with `cargo bench`
+    let mut scenes = std::env::args()
+        .skip(1)
+        .filter(|arg| !arg.starts_with("--"))
+        .map(PathBuf::from)
+        .collect::<Vec<_>>();
+    if scenes.is_empty() {
+        scenes = fs::read_dir("examples")
+            .expect("Could not list the example scenes")
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|path| path.extension() == Some(OsStr::new("toml")))
+            .collect();
+        scenes.sort();
+    }
+    for scene in &scenes {
+        if let Err(e) = bench_scene(scene) {
+            eprintln!("Skipping {}: {}", scene.display(), e);
+        }
+    }
+}
+
+fn bench_scene(path: &Path) -> Result<(), Box<dyn Error>> {
+    let config: BenchConfig = toml::from_str(&fs::read_to_string(path)?)?;
+    let [w, h] = config.params.resolution;
+    let camera = config.camera.build(w as f32 / h as f32, 0.0);
+    let rays = (0..w * h)
+        .filter_map(|i| {
+            let u = ((i % w) as f32 + 0.5) / w as f32;
+            let v = ((i / w) as f32 + 0.5) / h as f32;
+            camera.ray_at(u, v)
+        })
+        .collect::<Vec<_>>();
+    let SceneDesc {
+        objects,
+        meshes,
+        kd_tree,
+    } = config.scene;
+    let mesh_paths = objects
+        .into_iter()
+        .map(|object| object.geometry)
+        .chain(meshes.into_iter().map(|(_, mesh)| mesh))
+        .filter_map(|geometry| geometry.try_into::<Mesh>().ok())
+        .map(|mesh| mesh.path().to_owned())
+        .collect::<Vec<_>>();
+    if mesh_paths.is_empty() {
+        println!("{}: no meshes", path.display());
+    }
+    for mesh_path in &mesh_paths {
+        if let Err(e) = bench_mesh(path, mesh_path, &kd_tree, &rays) {
+            eprintln!("Skipping {}: {}", mesh_path.display(), e);
+        }
+    }
+    Ok(())
+}
+
+fn bench_mesh(
+    scene: &Path,
+    path: &Path,
+    kd_tree: &KdTreeConfig,
+    rays: &[Ray],
+) -> Result<(), Box<dyn Error>> {
+    let triangles = obj::parse(&fs::read_to_string(path)?);
+    println!(
+        "{}: {} ({} triangles, {} rays)",
+        scene.display(),
+        path.display(),
+        triangles.len(),
+        rays.len()
+    );
+    for &(accel, name) in &[(AccelType::KdTree, "kd-tree"), (AccelType::Bvh, "BVH")] {
+        let scalar = accel.build(triangles.clone(), kd_tree);
+        // Built straight from the triangles, so benchmarks don't write mesh caches
+        let mut packets = Mesh::new(path, Some(accel));
+        packets.build_from(triangles.clone(), accel, kd_tree);
+        let (scalar_hits, scalar_closest) = time(|| closest_hits(&scalar, rays));
+        let (packet_hits, packet_closest) = time(|| closest_hits(&packets, rays));
+        let (_, scalar_occluded) = time(|| occluded(&scalar, rays));
+        let (_, packet_occluded) = time(|| occluded(&packets, rays));
+        let mismatches = scalar_hits
+            .iter()
+            .zip(&packet_hits)
+            .filter(|(a, b)| a != b)
+            .count();
+        println!(
+            "  {:8} closest: {:>9.2?} scalar, {:>9.2?} packets  \
+             occluded: {:>9.2?} scalar, {:>9.2?} packets  mismatched hits: {}",
+            name, scalar_closest, packet_closest, scalar_occluded, packet_occluded, mismatches
+        );
+    }
+    Ok(())
+}
+
+/// Result of `f` along with the fastest of `RUNS` runs of it.
+fn time<T, F: FnMut() -> T>(mut f: F) -> (T, Duration) {
+    let mut best = Duration::MAX;
+    let mut result = None;
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        result = Some(f());
+        best = best.min(start.elapsed());
+    }
+    (result.unwrap(), best)
+}
+
+fn closest_hits<G: Geometry>(geometry: &G, rays: &[Ray]) -> Vec<Option<f32>> {
+    rays.iter()
+        .map(|ray| geometry.intersection(ray, 0.0, f32::MAX).map(|hit| hit.t))
+        .collect()
+}
+
+fn occluded<G: Geometry>(geometry: &G, rays: &[Ray]) -> usize {
+    rays.iter()
+        .filter(|ray| geometry.occluded(ray, 0.0, f32::MAX))
+        .count()
+}
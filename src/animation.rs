@@ -0,0 +1,56 @@
+use crate::geom::Transform;
+use crate::vec::{glm, Vec3};
+
+/// Values that can be blended between keyframes.
+pub trait Lerp: Clone {
+    fn lerp(&self, other: &Self, t: f32) -> Self;
+}
+
+impl Lerp for f32 {
+    fn lerp(&self, other: &f32, t: f32) -> f32 {
+        self + (other - self) * t
+    }
+}
+
+impl Lerp for Vec3 {
+    fn lerp(&self, other: &Vec3, t: f32) -> Vec3 {
+        glm::lerp(self, other, t)
+    }
+}
+
+impl Lerp for Transform {
+    fn lerp(&self, other: &Transform, t: f32) -> Transform {
+        Transform::lerp(self, other, t)
+    }
+}
+
+/// A set of property values that apply at one frame of an animation.
+pub trait Keyframe {
+    fn frame(&self) -> f32;
+}
+
+/// Value of the property selected by `get` at `frame`, interpolated linearly between the
+/// surrounding keys that set it and held constant before the first and after the last one.
+/// Returns `None` if no key sets the property.
+pub fn interpolate<K, T, F>(keys: &[K], frame: f32, get: F) -> Option<T>
+where
+    K: Keyframe,
+    T: Lerp,
+    F: Fn(&K) -> Option<&T>,
+{
+    let mut track = keys
+        .iter()
+        .filter_map(|key| get(key).map(|value| (key.frame(), value)))
+        .collect::<Vec<_>>();
+    track.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+    let next = track.iter().position(|(f, _)| *f > frame);
+    match next {
+        None => track.last().map(|(_, value)| (*value).clone()),
+        Some(0) => Some(track[0].1.clone()),
+        Some(i) => {
+            let (f0, v0) = track[i - 1];
+            let (f1, v1) = track[i];
+            Some(v0.lerp(v1, (frame - f0) / (f1 - f0)))
+        }
+    }
+}
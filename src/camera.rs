@@ -1,42 +1,323 @@
 use nalgebra_glm as glm;
+use serde::Deserialize;
 
+use crate::animation::{interpolate, Keyframe};
 use crate::ray::Ray;
-use crate::Vec3;
+use crate::{Vec2, Vec3};
 
 use std::f32::consts::PI;
 
+/// Placement, projection and lens of a camera.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CameraConfig {
+    pub position: Vec3,
+    pub look_at: Vec3,
+    pub up: Vec3,
+    /// Rotation around the viewing direction, in degrees
+    pub roll: f32,
+    pub projection: ProjectionType,
+    /// Vertical field of view of the perspective projection, in degrees
+    pub fov: f32,
+    /// Height of the orthographic view in world units
+    pub ortho_height: f32,
+    /// Horizontal and vertical coverage of the equirectangular projection, in degrees
+    pub panorama_fov: Vec2,
+    /// Field of view across the image circle of the fisheye projection, in degrees
+    pub fisheye_fov: f32,
+    /// Diameter of the lens, or 0 for a pinhole camera
+    pub aperture: f32,
+    /// Distance at which objects are in focus, by default the distance to `look_at`
+    pub focus_distance: Option<f32>,
+    /// Number of blades forming a polygonal aperture, or 0 for a round one
+    pub aperture_blades: u32,
+    /// Part of the frame, between 0 and 1, during which the shutter is open
+    pub shutter_open: f32,
+    pub shutter_close: f32,
+    /// Animated placement, overriding `position` and `look_at`
+    pub keyframes: Vec<CameraKey>,
+}
+
+/// Placement of a camera at one frame.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CameraKey {
+    pub frame: f32,
+    pub position: Option<Vec3>,
+    pub look_at: Option<Vec3>,
+}
+
+impl Keyframe for CameraKey {
+    fn frame(&self) -> f32 {
+        self.frame
+    }
+}
+
+impl Default for CameraConfig {
+    fn default() -> Self {
+        CameraConfig {
+            position: glm::vec3(0.0, 0.0, -1.0),
+            look_at: glm::zero(),
+            up: glm::vec3(0.0, 1.0, 0.0),
+            roll: 0.0,
+            projection: ProjectionType::Perspective,
+            fov: 80.0,
+            ortho_height: 2.0,
+            panorama_fov: glm::vec2(360.0, 180.0),
+            fisheye_fov: 180.0,
+            aperture: 0.0,
+            focus_distance: None,
+            aperture_blades: 0,
+            shutter_open: 0.0,
+            shutter_close: 0.0,
+            keyframes: Vec::new(),
+        }
+    }
+}
+
+impl CameraConfig {
+    /// Rejects shutter intervals outside the frame, where moving objects may lie
+    /// outside the bounds they are traced with.
+    pub fn validate(&self) -> Result<(), String> {
+        let (open, close) = (self.shutter_open, self.shutter_close);
+        if !(0.0 <= open && open <= close && close <= 1.0) {
+            return Err(format!(
+                "shutter_open ({}) and shutter_close ({}) must satisfy 0 <= open <= close <= 1",
+                open, close
+            ));
+        }
+        Ok(())
+    }
+
+    /// The camera as it is at `frame`.
+    pub fn build(&self, aspect: f32, frame: f32) -> Camera {
+        let projection = self.projection.build(self);
+        let keys = &self.keyframes;
+        let position = interpolate(keys, frame, |k| k.position.as_ref()).unwrap_or(self.position);
+        let look_at = interpolate(keys, frame, |k| k.look_at.as_ref()).unwrap_or(self.look_at);
+        Camera::looking_at(position, look_at, self.up, projection, aspect)
+            .with_roll(self.roll)
+            .with_lens(self.aperture, self.focus_distance, self.aperture_blades)
+            .with_shutter(self.shutter_open, self.shutter_close)
+    }
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ProjectionType {
+    Perspective,
+    Orthographic,
+    Equirectangular,
+    Fisheye,
+}
+
+impl ProjectionType {
+    pub fn build(self, params: &CameraConfig) -> Projection {
+        match self {
+            ProjectionType::Perspective => Projection::Perspective { fov: params.fov },
+            ProjectionType::Orthographic => Projection::Orthographic {
+                height: params.ortho_height,
+            },
+            ProjectionType::Equirectangular => Projection::Equirectangular {
+                fov: params.panorama_fov,
+            },
+            ProjectionType::Fisheye => Projection::Fisheye {
+                fov: params.fisheye_fov,
+            },
+        }
+    }
+}
+
+/// Mapping from image coordinates to rays leaving the camera. Angles are in degrees.
+#[derive(Clone, Copy)]
+pub enum Projection {
+    /// Pinhole projection onto a plane, with `fov` as the vertical field of view
+    Perspective { fov: f32 },
+    /// Parallel rays from a view of `height` world units
+    Orthographic { height: f32 },
+    /// Longitude and latitude mapped linearly to x and y, covering `fov` horizontally and vertically
+    Equirectangular { fov: Vec2 },
+    /// Equidistant fisheye in a circle fitting the image, covering `fov` across its diameter
+    Fisheye { fov: f32 },
+}
+
 pub struct Camera {
     position: Vec3,
-    bl_corner: Vec3,
-    horizontal: Vec3,
-    vertical: Vec3,
+    /// Right, down and backward axes of the camera
+    u: Vec3,
+    v: Vec3,
+    w: Vec3,
+    aspect: f32,
+    projection: Projection,
+    lens_radius: f32,
+    focus_distance: f32,
+    /// Number of straight edges of the aperture, or 0 for a round one
+    blades: u32,
+    /// Times at which the shutter opens and closes
+    shutter: (f32, f32),
 }
 
 impl Camera {
-    pub fn looking_at(position: Vec3, at: Vec3, up: Vec3, fov: f32, aspect: f32) -> Self {
-        let theta = fov * PI / 180.0;
-        let half_h = f32::tan(theta / 2.0);
-        let half_w = aspect * half_h;
-
+    pub fn looking_at(
+        position: Vec3,
+        at: Vec3,
+        up: Vec3,
+        projection: Projection,
+        aspect: f32,
+    ) -> Self {
         let w = glm::normalize(&(position - at));
+        // Looking along `up` leaves the sideways axis undefined, pick any other up vector
+        let up = if glm::length(&w.cross(&up)) < 1e-6 {
+            if w.x.abs() < 0.9 {
+                glm::vec3(1.0, 0.0, 0.0)
+            } else {
+                glm::vec3(0.0, 0.0, 1.0)
+            }
+        } else {
+            up
+        };
         let u: Vec3 = glm::normalize(&w.cross(&up));
         let v = w.cross(&u);
-
-        let bl_corner = position - half_w * u - half_h * v - w;
-        let horizontal = 2.0 * half_w * u;
-        let vertical = 2.0 * half_h * v;
         Camera {
             position,
-            bl_corner,
-            horizontal,
-            vertical,
+            u,
+            v,
+            w,
+            aspect,
+            projection,
+            lens_radius: 0.0,
+            focus_distance: glm::distance(&position, &at),
+            blades: 0,
+            shutter: (0.0, 0.0),
+        }
+    }
+
+    /// Rotates the camera by `degrees` around its viewing direction.
+    pub fn with_roll(self, degrees: f32) -> Self {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        Camera {
+            u: cos * self.u + sin * self.v,
+            v: cos * self.v - sin * self.u,
+            ..self
         }
     }
 
-    pub fn ray_at(&self, x: f32, y: f32) -> Ray {
-        Ray::new(
-            self.position,
-            self.bl_corner + x * self.horizontal + y * self.vertical - self.position,
-        )
+    /// Turns the pinhole into a thin lens with the given `aperture` diameter,
+    /// focused at `focus_distance` if given, or at the point the camera is looking at.
+    /// Depth of field only applies to the perspective and orthographic projections.
+    pub fn with_lens(self, aperture: f32, focus_distance: Option<f32>, blades: u32) -> Self {
+        Camera {
+            lens_radius: aperture / 2.0,
+            focus_distance: focus_distance.unwrap_or(self.focus_distance),
+            blades,
+            ..self
+        }
     }
+
+    /// Spreads the rays over the frame times from `open` to `close`.
+    pub fn with_shutter(self, open: f32, close: f32) -> Self {
+        Camera {
+            shutter: (open, close),
+            ..self
+        }
+    }
+
+    /// Ray through the center of the lens when the shutter opens, if the projection covers image position (`x`, `y`).
+    pub fn ray_at(&self, x: f32, y: f32) -> Option<Ray> {
+        let (sx, sy) = (2.0 * x - 1.0, 2.0 * y - 1.0);
+        let forward = -self.w;
+        let ray = match self.projection {
+            Projection::Perspective { fov } => {
+                let half_h = f32::tan(fov.to_radians() / 2.0);
+                let half_w = self.aspect * half_h;
+                let direction = forward + sx * half_w * self.u + sy * half_h * self.v;
+                Ray::new(self.position, direction)
+            }
+            Projection::Orthographic { height } => {
+                let (half_w, half_h) = (self.aspect * height / 2.0, height / 2.0);
+                let origin = self.position + sx * half_w * self.u + sy * half_h * self.v;
+                Ray::new(origin, forward)
+            }
+            Projection::Equirectangular { fov } => {
+                let phi = sx * fov.x.to_radians() / 2.0;
+                // Image y grows downwards, along `v`
+                let elevation = -sy * fov.y.to_radians() / 2.0;
+                let horizontal = f32::sin(phi) * self.u + f32::cos(phi) * forward;
+                let direction = f32::cos(elevation) * horizontal - f32::sin(elevation) * self.v;
+                Ray::new(self.position, direction)
+            }
+            Projection::Fisheye { fov } => {
+                // Fit the image circle into the shorter side
+                let (px, py) = if self.aspect > 1.0 {
+                    (sx * self.aspect, sy)
+                } else {
+                    (sx, sy / self.aspect)
+                };
+                let r = f32::sqrt(px * px + py * py);
+                if r > 1.0 {
+                    return None;
+                }
+                let theta = r * fov.to_radians() / 2.0;
+                let radial = if r > 0.0 {
+                    (px * self.u + py * self.v) / r
+                } else {
+                    glm::zero()
+                };
+                let direction = f32::cos(theta) * forward + f32::sin(theta) * radial;
+                Ray::new(self.position, direction)
+            }
+        };
+        Some(ray.with_time(self.shutter.0))
+    }
+
+    /// Ray through the point on the lens chosen by `lens`, which is in [0, 1)^2,
+    /// at the point in the shutter interval chosen by `time`.
+    pub fn sample_ray(&self, x: f32, y: f32, lens: Vec2, time: f32) -> Option<Ray> {
+        let (open, close) = self.shutter;
+        let ray = self.ray_at(x, y)?.with_time(open + (close - open) * time);
+        let planar = match self.projection {
+            Projection::Perspective { .. } | Projection::Orthographic { .. } => true,
+            Projection::Equirectangular { .. } | Projection::Fisheye { .. } => false,
+        };
+        if self.lens_radius <= 0.0 || !planar {
+            return Some(ray);
+        }
+        // Point on the plane of focus
+        let focus = ray.point_at(self.focus_distance / glm::dot(&ray.direction, &-self.w));
+        let p = if self.blades >= 3 {
+            polygon(self.blades, lens)
+        } else {
+            concentric_disk(lens)
+        } * self.lens_radius;
+        let origin = ray.origin + self.u * p.x + self.v * p.y;
+        Some(Ray::new(origin, focus - origin).with_time(ray.time))
+    }
+}
+
+/// Uniformly distributed point on the unit disk, using Shirley's concentric mapping.
+fn concentric_disk(u: Vec2) -> Vec2 {
+    let (a, b) = (2.0 * u.x - 1.0, 2.0 * u.y - 1.0);
+    if a == 0.0 && b == 0.0 {
+        return glm::zero();
+    }
+    let (r, theta) = if a.abs() > b.abs() {
+        (a, (PI / 4.0) * (b / a))
+    } else {
+        (b, PI / 2.0 - (PI / 4.0) * (a / b))
+    };
+    glm::vec2(r * f32::cos(theta), r * f32::sin(theta))
+}
+
+/// Uniformly distributed point on a regular polygon with `sides` corners on the unit circle.
+fn polygon(sides: u32, u: Vec2) -> Vec2 {
+    // Pick one of the triangles between the center and an edge, then a point inside it
+    let scaled = u.x * sides as f32;
+    let sector = f32::min(scaled.floor(), (sides - 1) as f32);
+    let s = f32::sqrt(scaled - sector);
+    let t = u.y;
+    let corner = |i: f32| {
+        let angle = i * 2.0 * PI / sides as f32;
+        glm::vec2(f32::cos(angle), f32::sin(angle))
+    };
+    (corner(sector) * (1.0 - t) + corner(sector + 1.0) * t) * s
 }
@@ -1,24 +1,64 @@
+use std::collections::HashMap;
 use std::error::Error;
 use std::fs;
 use std::path::Path;
 
-use nalgebra_glm::{zero, UVec2};
+use nalgebra_glm::UVec2;
 use serde::Deserialize;
 
+use crate::camera::CameraConfig;
+use crate::filter::Filter;
 use crate::geom::Scene;
-use crate::Vec3;
+use crate::hash;
+use crate::integrator::IntegratorType;
+use crate::output::OutputConfig;
+use crate::sampler::SamplerType;
+use crate::tonemap::{Tonemap, Transfer};
 
 #[derive(Deserialize)]
-#[serde(default)]
+#[serde(default, deny_unknown_fields)]
 pub struct RenderParams {
     pub resolution: UVec2,
+    /// Maximum number of samples per pixel
     pub samples: usize,
+    /// Samples every pixel takes before adaptive sampling may stop it,
+    /// or 16 but at most `samples` if unset
+    pub min_samples: Option<usize>,
+    /// Relative error at which a pixel stops receiving samples, or 0 to disable adaptive sampling
+    pub adaptive_threshold: f32,
+    /// Samples added to every pixel in each progressive pass
+    pub samples_per_pass: usize,
+    /// Width and height of the blocks of pixels rendered as one unit of work
+    pub tile_size: u32,
+    /// Seed for all random numbers, the same seed always produces the same image
+    pub seed: u64,
+    /// How sample values are distributed over the samples of a pixel
+    pub sampler: SamplerType,
+    /// Reconstruction filter that weights samples into the pixels around them
+    pub filter: Filter,
+    /// Extent of the filter in pixels, or the filter's own default if unset
+    pub filter_radius: Option<f32>,
+    /// Write the image every this many passes, or never if 0
+    pub checkpoint_passes: usize,
+    /// Write the image whenever this many seconds passed since the last time, or never if 0
+    pub checkpoint_seconds: u64,
+    pub integrator: IntegratorType,
     pub max_light_bounces: usize,
+    /// Number of bounces after which paths may be terminated by Russian roulette
+    pub russian_roulette_depth: usize,
+    /// Occlusion rays cast per camera ray by the ambient occlusion integrator
+    pub ao_samples: usize,
+    /// Maximum distance at which geometry still occludes
+    pub ao_distance: f32,
     pub gamma: f32,
     pub exposure: f32,
-    pub camera_pos: Vec3,
-    pub looking_at: Vec3,
-    pub fov: f32,
+    pub tonemap: Tonemap,
+    /// Smallest value mapped to pure white by the extended Reinhard curve
+    pub white_point: f32,
+    pub transfer: Transfer,
+    /// First and last frame of an animation to render as a numbered image sequence,
+    /// or a single image at frame 0 if unset
+    pub frames: Option<UVec2>,
 }
 
 impl Default for RenderParams {
@@ -26,26 +66,183 @@ impl Default for RenderParams {
         RenderParams {
             resolution: UVec2::new(500, 500),
             samples: 10,
+            min_samples: None,
+            adaptive_threshold: 0.0,
+            samples_per_pass: 1,
+            tile_size: 32,
+            seed: 0,
+            sampler: SamplerType::Sobol,
+            filter: Filter::Box,
+            filter_radius: None,
+            checkpoint_passes: 0,
+            checkpoint_seconds: 60,
+            integrator: IntegratorType::Path,
             max_light_bounces: 5,
+            russian_roulette_depth: 3,
+            ao_samples: 4,
+            ao_distance: 1.0,
             gamma: 2.2,
             exposure: 1.0,
-            camera_pos: Vec3::new(0.0, 0.0, -1.0),
-            looking_at: zero(),
-            fov: 80.0,
+            tonemap: Tonemap::Exponential,
+            white_point: 4.0,
+            transfer: Transfer::Gamma,
+            frames: None,
         }
     }
 }
 
+impl RenderParams {
+    pub fn min_samples(&self) -> usize {
+        self.min_samples.unwrap_or(usize::min(16, self.samples))
+    }
+
+    /// Rejects settings that can't produce a meaningful image.
+    fn validate(&self) -> Result<(), String> {
+        if self.min_samples() > self.samples {
+            return Err(format!(
+                "min_samples ({}) must not be greater than samples ({})",
+                self.min_samples(),
+                self.samples
+            ));
+        }
+        if self.ao_samples == 0 {
+            return Err("ao_samples must be at least 1".to_owned());
+        }
+        if self.samples_per_pass == 0 {
+            return Err("samples_per_pass must be at least 1".to_owned());
+        }
+        Ok(())
+    }
+}
+
 #[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct UserConfig {
     pub params: RenderParams,
     pub scene: Scene,
+    #[serde(default)]
+    pub output: OutputConfig,
+    #[serde(default)]
+    pub camera: CameraConfig,
+    /// Additional cameras that can be picked by name instead of `camera`
+    #[serde(default)]
+    pub cameras: HashMap<String, CameraConfig>,
+    /// Hash of the file's contents, which covers the scene and every setting
+    #[serde(skip)]
+    pub hash: u64,
 }
 
 impl UserConfig {
     pub fn from_file(path: &Path) -> Result<Self, Box<dyn Error + '_>> {
         let contents = fs::read_to_string(path)?;
-        let cfg = toml::from_str(&contents)?;
+        let mut cfg: UserConfig = toml::from_str(&contents)?;
+        cfg.params.validate()?;
+        cfg.camera.validate()?;
+        for (name, camera) in &cfg.cameras {
+            camera
+                .validate()
+                .map_err(|e| format!("Camera {}: {}", name, e))?;
+        }
+        cfg.hash = hash::fnv1a(contents.as_bytes());
         Ok(cfg)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults_are_valid() {
+        assert!(RenderParams::default().validate().is_ok());
+    }
+
+    #[test]
+    fn default_min_samples_fit_in_samples() {
+        let few = RenderParams {
+            samples: 4,
+            ..RenderParams::default()
+        };
+        assert_eq!(few.min_samples(), 4);
+        assert!(few.validate().is_ok());
+        let many = RenderParams {
+            samples: 400,
+            ..RenderParams::default()
+        };
+        assert_eq!(many.min_samples(), 16);
+    }
+
+    #[test]
+    fn rejects_more_min_samples_than_samples() {
+        let params = RenderParams {
+            samples: 16,
+            min_samples: Some(32),
+            ..RenderParams::default()
+        };
+        assert!(params.validate().is_err());
+    }
+
+    #[test]
+    fn rejects_legacy_camera_keys() {
+        let legacy = "camera_pos = [0.0, 1.0, -5.0]\nfov = 60.0\n";
+        let error = toml::from_str::<RenderParams>(legacy).err().unwrap();
+        assert!(error.to_string().contains("camera_pos"));
+    }
+
+    #[test]
+    fn example_params_are_valid() {
+        let examples = [
+            include_str!("../examples/example1.toml"),
+            include_str!("../examples/example2.toml"),
+            include_str!("../examples/monkey.toml"),
+            include_str!("../examples/dragon.toml"),
+        ];
+        for example in &examples {
+            let config: toml::Value = toml::from_str(example).unwrap();
+            let params: RenderParams = config["params"].clone().try_into().unwrap();
+            assert!(params.validate().is_ok());
+            let named = config.get("cameras").and_then(toml::Value::as_table);
+            let cameras = config
+                .get("camera")
+                .into_iter()
+                .chain(named.into_iter().flat_map(|c| c.values()));
+            for camera in cameras {
+                let camera = camera.clone().try_into::<CameraConfig>().unwrap();
+                assert!(camera.validate().is_ok());
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_shutters_outside_the_frame() {
+        let shutter = |open, close| CameraConfig {
+            shutter_open: open,
+            shutter_close: close,
+            ..CameraConfig::default()
+        };
+        assert!(shutter(0.0, 0.0).validate().is_ok());
+        assert!(shutter(0.25, 1.0).validate().is_ok());
+        assert!(shutter(-0.5, 0.5).validate().is_err());
+        assert!(shutter(0.5, 1.5).validate().is_err());
+        assert!(shutter(0.75, 0.25).validate().is_err());
+        assert!(shutter(f32::NAN, 0.5).validate().is_err());
+    }
+
+    #[test]
+    fn rejects_zero_ao_samples() {
+        let params = RenderParams {
+            ao_samples: 0,
+            ..RenderParams::default()
+        };
+        assert!(params.validate().is_err());
+    }
+
+    #[test]
+    fn rejects_zero_samples_per_pass() {
+        let params = RenderParams {
+            samples_per_pass: 0,
+            ..RenderParams::default()
+        };
+        assert!(params.validate().is_err());
+    }
+}
@@ -0,0 +1,443 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use indicatif::ProgressBar;
+use rayon::prelude::*;
+
+use crate::filter::PixelFilter;
+use crate::vec::{glm, Vec2, Vec3};
+
+const MAGIC: &[u8; 8] = b"PRAYACC4";
+
+/// Limits on the number of samples taken in each pixel.
+pub struct SampleBudget {
+    pub min: usize,
+    pub max: usize,
+    /// Relative error below which a pixel stops receiving samples, or 0 to always take `max`
+    pub threshold: f32,
+}
+
+/// Running statistics of the samples taken in a single pixel.
+#[derive(Clone)]
+struct Pixel {
+    sum: Vec3,
+    /// Sum of the squared luminance of each sample, for the variance estimate
+    lum_sq: f32,
+    count: u32,
+}
+
+impl Default for Pixel {
+    fn default() -> Self {
+        Pixel {
+            sum: glm::zero(),
+            lum_sq: 0.0,
+            count: 0,
+        }
+    }
+}
+
+impl Pixel {
+    fn add(&mut self, sample: Vec3) {
+        let lum = luminance(&sample);
+        self.sum += sample;
+        self.lum_sq += lum * lum;
+        self.count += 1;
+    }
+
+    /// Standard error of the mean luminance, relative to the mean itself.
+    fn relative_error(&self) -> f32 {
+        if self.count < 2 {
+            return f32::INFINITY;
+        }
+        let n = self.count as f32;
+        let mean = luminance(&self.sum) / n;
+        let variance = f32::max(0.0, (self.lum_sq - mean * mean * n) / (n - 1.0));
+        f32::sqrt(variance / n) / f32::max(mean, 1e-3)
+    }
+
+    fn needs_samples(&self, budget: &SampleBudget) -> bool {
+        let count = self.count as usize;
+        if count >= budget.max {
+            false
+        } else if count < budget.min || budget.threshold <= 0.0 {
+            true
+        } else {
+            self.relative_error() >= budget.threshold
+        }
+    }
+}
+
+/// Filter-weighted sum of the samples around a single pixel.
+#[derive(Clone)]
+struct Splat {
+    sum: Vec3,
+    weight: f32,
+}
+
+impl Default for Splat {
+    fn default() -> Self {
+        Splat {
+            sum: glm::zero(),
+            weight: 0.0,
+        }
+    }
+}
+
+fn luminance(color: &Vec3) -> f32 {
+    glm::dot(color, &glm::vec3(0.2126, 0.7152, 0.0722))
+}
+
+/// Rectangular block of the image that is rendered as one unit of work.
+struct Tile {
+    x: u32,
+    y: u32,
+    width: u32,
+    pixels: Vec<Pixel>,
+}
+
+/// Splats of the samples taken in one tile, covering every pixel
+/// within the filter radius of the tile.
+struct SplatTile {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    splats: Vec<Splat>,
+}
+
+impl SplatTile {
+    fn around(tile: &Tile, margin: u32, (width, height): (u32, u32)) -> Self {
+        let tile_h = tile.pixels.len() as u32 / tile.width;
+        let x = tile.x.saturating_sub(margin);
+        let y = tile.y.saturating_sub(margin);
+        let x_end = u32::min(tile.x + tile.width + margin, width);
+        let y_end = u32::min(tile.y + tile_h + margin, height);
+        SplatTile {
+            x,
+            y,
+            width: x_end - x,
+            height: y_end - y,
+            splats: vec![Splat::default(); ((x_end - x) * (y_end - y)) as usize],
+        }
+    }
+
+    /// Adds `radiance` to every pixel within the filter radius of the sample
+    /// at `offset` inside pixel (`x`, `y`).
+    fn add(&mut self, (x, y): (u32, u32), offset: Vec2, radiance: &Vec3, filter: &PixelFilter) {
+        let r = filter.radius();
+        // Distance from the sample to the center of pixel `i`, relative to pixel `p`
+        let dist = |p: u32, offset: f32, i: i64| (i64::from(p) - i) as f32 + offset - 0.5;
+        // Pixels further away than the radius get zero weight from the filter
+        let reach = (r + 1.0).ceil() as i64;
+        let range = |p: u32, start: u32, len: u32| {
+            let lo = i64::max(i64::from(p) - reach, i64::from(start));
+            let hi = i64::min(i64::from(p) + reach, i64::from(start + len) - 1);
+            lo..=hi
+        };
+        for j in range(y, self.y, self.height) {
+            for i in range(x, self.x, self.width) {
+                let weight = filter.weight(dist(x, offset.x, i), dist(y, offset.y, j));
+                if weight != 0.0 {
+                    let idx = (j as u32 - self.y) * self.width + (i as u32 - self.x);
+                    let splat = &mut self.splats[idx as usize];
+                    splat.sum += radiance * weight;
+                    splat.weight += weight;
+                }
+            }
+        }
+    }
+}
+
+/// Accumulates radiance samples over several passes of the whole image.
+pub struct Film {
+    pub width: u32,
+    pub height: u32,
+    tile_size: u32,
+    tiles_x: u32,
+    tiles: Vec<Tile>,
+    filter: PixelFilter,
+    /// Filtered samples of all pixels in row-major order
+    splats: Vec<Splat>,
+}
+
+impl Film {
+    pub fn new(width: u32, height: u32, tile_size: u32, filter: PixelFilter) -> Self {
+        let pixels = vec![Pixel::default(); (width * height) as usize];
+        let splats = vec![Splat::default(); (width * height) as usize];
+        Self::from_pixels(width, height, tile_size, filter, pixels, splats)
+    }
+
+    /// Splits the row-major `pixels` into tiles.
+    fn from_pixels(
+        width: u32,
+        height: u32,
+        tile_size: u32,
+        filter: PixelFilter,
+        pixels: Vec<Pixel>,
+        splats: Vec<Splat>,
+    ) -> Self {
+        let tile_size = u32::max(tile_size, 1);
+        let tiles_x = width.div_ceil(tile_size);
+        let tiles_y = height.div_ceil(tile_size);
+        let tiles = (0..tiles_y)
+            .flat_map(|ty| (0..tiles_x).map(move |tx| (tx * tile_size, ty * tile_size)))
+            .map(|(x, y)| {
+                let tile_w = u32::min(tile_size, width - x);
+                let tile_h = u32::min(tile_size, height - y);
+                let pixels = (y..y + tile_h)
+                    .flat_map(|py| (x..x + tile_w).map(move |px| (py * width + px) as usize))
+                    .map(|i| pixels[i].clone())
+                    .collect();
+                Tile {
+                    x,
+                    y,
+                    width: tile_w,
+                    pixels,
+                }
+            })
+            .collect();
+        Film {
+            width,
+            height,
+            tile_size,
+            tiles_x,
+            tiles,
+            filter,
+            splats,
+        }
+    }
+
+    fn pixel(&self, x: u32, y: u32) -> &Pixel {
+        let tile = &self.tiles[((y / self.tile_size) * self.tiles_x + x / self.tile_size) as usize];
+        &tile.pixels[((y - tile.y) * tile.width + (x - tile.x)) as usize]
+    }
+
+    /// All pixels in row-major order.
+    fn row_major(&self) -> impl Iterator<Item = &Pixel> {
+        (0..self.height).flat_map(move |y| (0..self.width).map(move |x| self.pixel(x, y)))
+    }
+
+    /// Smallest number of samples taken in any pixel.
+    pub fn min_samples(&self) -> usize {
+        self.row_major().map(|p| p.count).min().unwrap_or(0) as usize
+    }
+
+    /// Number of samples taken in every pixel.
+    pub fn sample_counts(&self) -> Vec<u32> {
+        self.row_major().map(|p| p.count).collect()
+    }
+
+    /// Adds up to `count` samples to every pixel that has not converged yet according to `budget`,
+    /// taking them from `sample(x, y, index)`. Returns the number of pixels that were sampled.
+    ///
+    /// `index` counts the samples already taken in the pixel, so that samples can be
+    /// generated independently of how tiles are scheduled. `sample` returns the position
+    /// of the sample inside the pixel along with its radiance, which is splatted into
+    /// all pixels within the filter radius.
+    pub fn add_samples<F>(
+        &mut self,
+        count: usize,
+        budget: &SampleBudget,
+        pb: &ProgressBar,
+        sample: F,
+    ) -> usize
+    where
+        F: Fn(u32, u32, u32) -> (Vec2, Vec3) + Sync,
+    {
+        let filter = self.filter;
+        let margin = (filter.radius() + 0.5).ceil() as u32;
+        let dims = (self.width, self.height);
+        let results = self
+            .tiles
+            .par_iter_mut()
+            .map(|tile| {
+                let mut splats = SplatTile::around(tile, margin, dims);
+                let tile_w = tile.width;
+                let (tile_x, tile_y) = (tile.x, tile.y);
+                let sampled = tile
+                    .pixels
+                    .iter_mut()
+                    .enumerate()
+                    .filter(|(_, pixel)| pixel.needs_samples(budget))
+                    .map(|(i, pixel)| {
+                        let x = tile_x + i as u32 % tile_w;
+                        let y = tile_y + i as u32 / tile_w;
+                        let count = usize::min(count, budget.max - pixel.count as usize);
+                        for _ in 0..count {
+                            let (offset, radiance) = sample(x, y, pixel.count);
+                            pixel.add(radiance);
+                            splats.add((x, y), offset, &radiance, &filter);
+                        }
+                    })
+                    .count();
+                pb.inc(tile.pixels.len() as u64);
+                (sampled, splats)
+            })
+            .collect::<Vec<_>>();
+        // Tiles overlap within the filter radius, so their splats are merged one at a time
+        let mut sampled = 0;
+        for (count, tile) in results {
+            sampled += count;
+            self.merge(&tile);
+        }
+        sampled
+    }
+
+    fn merge(&mut self, tile: &SplatTile) {
+        for ty in 0..tile.height {
+            for tx in 0..tile.width {
+                let splat = &tile.splats[(ty * tile.width + tx) as usize];
+                let target = &mut self.splats[((tile.y + ty) * self.width + tile.x + tx) as usize];
+                target.sum += splat.sum;
+                target.weight += splat.weight;
+            }
+        }
+    }
+
+    /// Current filtered estimate of every pixel.
+    pub fn pixels(&self) -> Vec<Vec3> {
+        self.row_major()
+            .zip(&self.splats)
+            .map(|(p, splat)| {
+                if splat.weight > 0.0 {
+                    splat.sum / splat.weight
+                } else {
+                    // Negative lobes can cancel out all weight, fall back to the plain average
+                    p.sum / u32::max(p.count, 1) as f32
+                }
+            })
+            .collect()
+    }
+
+    /// Stores the accumulated samples, so that rendering can resume later
+    /// with the same `settings`.
+    pub fn save(&self, path: &Path, settings: u64) -> io::Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(MAGIC)?;
+        file.write_all(&settings.to_le_bytes())?;
+        file.write_all(&self.width.to_le_bytes())?;
+        file.write_all(&self.height.to_le_bytes())?;
+        for (pixel, splat) in self.row_major().zip(&self.splats) {
+            for c in pixel.sum.iter().chain(splat.sum.iter()) {
+                file.write_all(&c.to_le_bytes())?;
+            }
+            file.write_all(&pixel.lum_sq.to_le_bytes())?;
+            file.write_all(&pixel.count.to_le_bytes())?;
+            file.write_all(&splat.weight.to_le_bytes())?;
+        }
+        file.flush()
+    }
+
+    /// Reads back samples stored by `save`, which must have been given the same `settings`.
+    pub fn load(
+        path: &Path,
+        settings: u64,
+        tile_size: u32,
+        filter: PixelFilter,
+    ) -> io::Result<Self> {
+        let mut file = BufReader::new(File::open(path)?);
+        let mut magic = [0; 8];
+        file.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Not an accumulation buffer",
+            ));
+        }
+        let mut saved = [0; 8];
+        file.read_exact(&mut saved)?;
+        if u64::from_le_bytes(saved) != settings {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Accumulation buffer was rendered with different settings",
+            ));
+        }
+        let width = read_u32(&mut file)?;
+        let height = read_u32(&mut file)?;
+        let (pixels, splats) = (0..width * height)
+            .map(|_| {
+                let sum = read_vec3(&mut file)?;
+                let filtered = read_vec3(&mut file)?;
+                let lum_sq = read_f32(&mut file)?;
+                let count = read_u32(&mut file)?;
+                let weight = read_f32(&mut file)?;
+                Ok((
+                    Pixel { sum, lum_sq, count },
+                    Splat {
+                        sum: filtered,
+                        weight,
+                    },
+                ))
+            })
+            .collect::<io::Result<Vec<_>>>()?
+            .into_iter()
+            .unzip();
+        Ok(Self::from_pixels(
+            width, height, tile_size, filter, pixels, splats,
+        ))
+    }
+}
+
+fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_f32<R: Read>(r: &mut R) -> io::Result<f32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(f32::from_le_bytes(buf))
+}
+
+fn read_vec3<R: Read>(r: &mut R) -> io::Result<Vec3> {
+    Ok(glm::vec3(read_f32(r)?, read_f32(r)?, read_f32(r)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::Filter;
+
+    fn sample(x: u32, y: u32, index: u32) -> (Vec2, Vec3) {
+        let offset = glm::vec2(
+            (index % 3) as f32 * 0.3 + 0.1,
+            (index % 2) as f32 * 0.5 + 0.2,
+        );
+        let radiance = glm::vec3(x as f32, y as f32 * 0.5, index as f32 * 0.25);
+        (offset, radiance)
+    }
+
+    fn budget(max: usize) -> SampleBudget {
+        SampleBudget {
+            min: max,
+            max,
+            threshold: 0.0,
+        }
+    }
+
+    #[test]
+    fn accumulation_buffer_round_trips() {
+        let path = std::env::temp_dir().join("prayer_film_round_trip_test.accum");
+        let filter = PixelFilter::new(Filter::Gaussian, None);
+        let pb = ProgressBar::hidden();
+        let mut film = Film::new(5, 3, 2, filter);
+        film.add_samples(3, &budget(3), &pb, sample);
+        film.save(&path, 42).unwrap();
+
+        let mut loaded = Film::load(&path, 42, 2, filter).unwrap();
+        let stale = Film::load(&path, 43, 2, filter);
+        std::fs::remove_file(&path).unwrap();
+        assert!(stale.is_err());
+        assert_eq!((loaded.width, loaded.height), (5, 3));
+        assert_eq!(loaded.sample_counts(), film.sample_counts());
+        assert_eq!(loaded.pixels(), film.pixels());
+
+        // Resuming continues exactly where the saved render stopped
+        film.add_samples(2, &budget(5), &pb, sample);
+        loaded.add_samples(2, &budget(5), &pb, sample);
+        assert_eq!(loaded.sample_counts(), film.sample_counts());
+        assert_eq!(loaded.pixels(), film.pixels());
+    }
+}
@@ -0,0 +1,180 @@
+use serde::Deserialize;
+
+use std::f32::consts::PI;
+
+/// Weighting of the samples around a pixel when reconstructing its value.
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Filter {
+    /// Equal weight for every sample inside the radius
+    Box,
+    /// Weight falling off linearly towards the radius
+    Tent,
+    /// Gaussian with a standard deviation of a third of the radius
+    Gaussian,
+    /// Mitchell-Netravali cubic with B = C = 1/3
+    Mitchell,
+    /// Windowed sinc with as many lobes as the radius
+    Lanczos,
+}
+
+impl Filter {
+    /// Radius used when none is configured.
+    pub fn default_radius(self) -> f32 {
+        match self {
+            Filter::Box => 0.5,
+            Filter::Tent => 1.0,
+            Filter::Gaussian => 1.5,
+            Filter::Mitchell => 2.0,
+            Filter::Lanczos => 3.0,
+        }
+    }
+
+    /// Weight of a sample at distance `x` along one axis from the pixel center.
+    fn eval(self, x: f32, radius: f32) -> f32 {
+        if x < -radius || x >= radius {
+            return 0.0;
+        }
+        let x = x.abs();
+        match self {
+            Filter::Box => 1.0,
+            Filter::Tent => radius - x,
+            Filter::Gaussian => {
+                let sigma = radius / 3.0;
+                let gaussian = |x: f32| f32::exp(-x * x / (2.0 * sigma * sigma));
+                f32::max(0.0, gaussian(x) - gaussian(radius))
+            }
+            Filter::Mitchell => {
+                let (b, c) = (1.0 / 3.0, 1.0 / 3.0);
+                let x = 2.0 * x / radius;
+                let poly = if x < 1.0 {
+                    (12.0 - 9.0 * b - 6.0 * c) * x * x * x
+                        + (-18.0 + 12.0 * b + 6.0 * c) * x * x
+                        + (6.0 - 2.0 * b)
+                } else {
+                    (-b - 6.0 * c) * x * x * x
+                        + (6.0 * b + 30.0 * c) * x * x
+                        + (-12.0 * b - 48.0 * c) * x
+                        + (8.0 * b + 24.0 * c)
+                };
+                poly / 6.0
+            }
+            Filter::Lanczos => sinc(x) * sinc(x / radius),
+        }
+    }
+}
+
+fn sinc(x: f32) -> f32 {
+    if x < 1e-5 {
+        1.0
+    } else {
+        f32::sin(PI * x) / (PI * x)
+    }
+}
+
+/// A filter together with the radius it extends over, in pixels.
+#[derive(Clone, Copy)]
+pub struct PixelFilter {
+    filter: Filter,
+    radius: f32,
+}
+
+impl PixelFilter {
+    pub fn new(filter: Filter, radius: Option<f32>) -> Self {
+        PixelFilter {
+            filter,
+            radius: radius.unwrap_or_else(|| filter.default_radius()),
+        }
+    }
+
+    pub fn radius(&self) -> f32 {
+        self.radius
+    }
+
+    /// Weight of a sample at offset (`dx`, `dy`) from the pixel center.
+    pub fn weight(&self, dx: f32, dy: f32) -> f32 {
+        self.filter.eval(dx, self.radius) * self.filter.eval(dy, self.radius)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [Filter; 5] = [
+        Filter::Box,
+        Filter::Tent,
+        Filter::Gaussian,
+        Filter::Mitchell,
+        Filter::Lanczos,
+    ];
+
+    /// Offsets from the center to the default radius of `filter`, in `steps` steps.
+    fn offsets(filter: Filter, steps: u32) -> impl Iterator<Item = f32> {
+        let radius = filter.default_radius();
+        (0..steps).map(move |i| i as f32 / steps as f32 * radius)
+    }
+
+    #[test]
+    fn weights_vanish_outside_the_radius() {
+        for &filter in &ALL {
+            let pixel = PixelFilter::new(filter, None);
+            let r = pixel.radius();
+            assert_eq!(pixel.weight(r, 0.0), 0.0);
+            assert_eq!(pixel.weight(0.0, -r - 0.1), 0.0);
+            assert_eq!(pixel.weight(r + 1.0, r + 1.0), 0.0);
+        }
+    }
+
+    #[test]
+    fn weights_are_symmetric_and_peak_at_the_center() {
+        for &filter in &ALL {
+            let pixel = PixelFilter::new(filter, None);
+            let center = pixel.weight(0.0, 0.0);
+            assert!(center > 0.0);
+            for x in offsets(filter, 20) {
+                assert_eq!(pixel.weight(x, 0.3), pixel.weight(-x, 0.3));
+                assert_eq!(pixel.weight(x, 0.3), pixel.weight(0.3, x));
+                assert!(pixel.weight(x, 0.0) <= center);
+            }
+        }
+    }
+
+    #[test]
+    fn custom_radius_overrides_the_default() {
+        let pixel = PixelFilter::new(Filter::Tent, Some(2.5));
+        assert_eq!(pixel.radius(), 2.5);
+        assert!(pixel.weight(2.0, 0.0) > 0.0);
+    }
+
+    #[test]
+    fn box_and_tent_shapes() {
+        for x in offsets(Filter::Box, 10) {
+            assert_eq!(Filter::Box.eval(x, 0.5), 1.0);
+        }
+        assert_eq!(Filter::Tent.eval(0.25, 1.0), 0.75);
+        assert_eq!(Filter::Tent.eval(-0.5, 1.0), 0.5);
+    }
+
+    #[test]
+    fn smooth_filters_are_continuous() {
+        for &filter in &[Filter::Gaussian, Filter::Mitchell, Filter::Lanczos] {
+            let radius = filter.default_radius();
+            let weights = offsets(filter, 1000)
+                .map(|x| filter.eval(x, radius))
+                .collect::<Vec<_>>();
+            assert!(weights.windows(2).all(|w| (w[0] - w[1]).abs() < 0.01));
+            // Also continuous where the weight drops to zero at the radius
+            assert!(weights.last().unwrap().abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn negative_lobes() {
+        assert!(Filter::Mitchell.eval(1.5, 2.0) < 0.0);
+        assert!(Filter::Lanczos.eval(1.5, 3.0) < 0.0);
+        for x in 1..3 {
+            assert!(Filter::Lanczos.eval(x as f32, 3.0).abs() < 1e-6);
+        }
+    }
+}
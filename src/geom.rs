@@ -1,37 +1,117 @@
 mod aabb;
+mod accel;
+mod bvh;
+mod cache;
+mod instance;
 mod kdtree;
 mod mesh;
+mod packet;
 mod plane;
 mod scene;
 mod sphere;
+mod transform;
 
 use serde::Deserialize;
 
 pub use self::aabb::*;
+pub use self::accel::*;
+pub use self::bvh::*;
+pub use self::instance::*;
 pub use self::kdtree::*;
 pub use self::mesh::*;
+pub use self::packet::*;
 pub use self::plane::*;
 pub use self::scene::*;
 pub use self::sphere::*;
+pub use self::transform::*;
 
+use crate::animation::{interpolate, Keyframe};
 use crate::material::Material;
 use crate::ray::Ray;
+use crate::texture::{ColorTexture, GrayScaleTexture};
 
+use crate::vec::glm;
 use crate::{Vec2, Vec3};
 
 pub trait Geometry {
     fn intersection(&self, ray: &Ray, min: f32, max: f32) -> Option<RayHit>;
+
+    /// Whether anything blocks `ray` between `min` and `max`, which can stop at any hit
+    /// instead of looking for the closest one.
+    fn occluded(&self, ray: &Ray, min: f32, max: f32) -> bool {
+        self.intersection(ray, min, max).is_some()
+    }
 }
 
 pub trait Traceable {
-    fn trace(&self, ray: &Ray, min: f32, max: f32) -> Option<TraceResult>;
+    fn trace(&self, ray: &Ray, min: f32, max: f32) -> Option<TraceResult<'_>>;
+
+    /// Whether anything blocks `ray` between `min` and `max`, see `Geometry::occluded`.
+    fn occluded(&self, ray: &Ray, min: f32, max: f32) -> bool {
+        self.trace(ray, min, max).is_some()
+    }
+}
+
+/// Geometry that can be sampled directly, used for light sampling.
+pub trait Emitter {
+    /// Samples a point on the surface at `time` as seen from `origin`,
+    /// using the random numbers in `u`.
+    fn sample_towards(&self, origin: &Vec3, time: f32, u: Vec2) -> Option<SurfaceSample>;
+
+    /// Solid angle density of `sample_towards` returning `hit` as seen from `origin`.
+    fn pdf_towards(&self, origin: &Vec3, hit: &RayHit) -> f32;
 }
 
 pub struct RayHit {
     pub t: f32,
     pub point: Vec3,
+    /// Shading normal, which may be interpolated away from the surface's own normal
     pub normal: Vec3,
+    /// Normal of the surface itself, which light densities are measured against
+    pub geometric_normal: Vec3,
     pub uv: Vec2,
+    /// Time of the ray that found the hit
+    pub time: f32,
+}
+
+impl RayHit {
+    /// Ray leaving the hit point in `direction` at the same time.
+    pub fn spawn(&self, direction: Vec3) -> Ray {
+        Ray::new(self.point, direction).with_time(self.time)
+    }
+}
+
+pub struct SurfaceSample {
+    pub point: Vec3,
+    /// Normal of the surface itself, like `RayHit::geometric_normal`
+    pub normal: Vec3,
+    pub uv: Vec2,
+    /// Density with respect to solid angle at the reference point
+    pub pdf: f32,
+}
+
+/// Converts an area density at `point` into a solid angle density as seen from `origin`.
+pub fn area_to_solid_angle(pdf: f32, origin: &Vec3, point: &Vec3, normal: &Vec3) -> f32 {
+    let to_point = point - origin;
+    let dist2 = glm::dot(&to_point, &to_point);
+    let cos = f32::abs(glm::dot(normal, &to_point)) / f32::sqrt(dist2);
+    if cos > 0.0 {
+        pdf * dist2 / cos
+    } else {
+        0.0
+    }
+}
+
+/// Converts a solid angle density at `point` as seen from `origin` into an area density.
+pub fn solid_angle_to_area(pdf: f32, origin: &Vec3, point: &Vec3, normal: &Vec3) -> f32 {
+    let to_point = point - origin;
+    let dist2 = glm::dot(&to_point, &to_point);
+    let cos = f32::abs(glm::dot(normal, &to_point)) / f32::sqrt(dist2);
+    if dist2 > 0.0 {
+        pdf * cos / dist2
+    } else {
+        0.0
+    }
 }
 
 #[derive(Deserialize)]
@@ -40,6 +120,7 @@ pub enum GeomType {
     Sphere(Sphere),
     Plane(Plane),
     Mesh(Mesh),
+    Instance(Instance),
 }
 
 impl Geometry for GeomType {
@@ -48,6 +129,36 @@ impl Geometry for GeomType {
             GeomType::Sphere(s) => s.intersection(ray, min, max),
             GeomType::Plane(p) => p.intersection(ray, min, max),
             GeomType::Mesh(m) => m.intersection(ray, min, max),
+            GeomType::Instance(i) => i.intersection(ray, min, max),
+        }
+    }
+
+    fn occluded(&self, ray: &Ray, min: f32, max: f32) -> bool {
+        match self {
+            GeomType::Sphere(s) => s.occluded(ray, min, max),
+            GeomType::Plane(p) => p.occluded(ray, min, max),
+            GeomType::Mesh(m) => m.occluded(ray, min, max),
+            GeomType::Instance(i) => i.occluded(ray, min, max),
+        }
+    }
+}
+
+impl Emitter for GeomType {
+    fn sample_towards(&self, origin: &Vec3, time: f32, u: Vec2) -> Option<SurfaceSample> {
+        match self {
+            GeomType::Sphere(s) => s.sample_towards(origin, time, u),
+            GeomType::Plane(p) => p.sample_towards(origin, time, u),
+            GeomType::Mesh(m) => m.sample_towards(origin, time, u),
+            GeomType::Instance(i) => i.sample_towards(origin, time, u),
+        }
+    }
+
+    fn pdf_towards(&self, origin: &Vec3, hit: &RayHit) -> f32 {
+        match self {
+            GeomType::Sphere(s) => s.pdf_towards(origin, hit),
+            GeomType::Plane(p) => p.pdf_towards(origin, hit),
+            GeomType::Mesh(m) => m.pdf_towards(origin, hit),
+            GeomType::Instance(i) => i.pdf_towards(origin, hit),
         }
     }
 }
@@ -58,6 +169,7 @@ impl Bounds for GeomType {
             GeomType::Sphere(s) => s.bounds(),
             GeomType::Plane(p) => p.bounds(),
             GeomType::Mesh(m) => m.bounds(),
+            GeomType::Instance(i) => i.bounds(),
         }
     }
 }
@@ -66,20 +178,174 @@ impl Bounds for GeomType {
 pub struct Object {
     pub geometry: GeomType,
     pub material: Material,
+    /// Placement of the geometry in the scene, applied before `motion`
+    #[serde(default)]
+    pub transform: Option<Affine>,
+    /// Movement of the whole object during the frame. Objects with transform keys
+    /// get theirs from the keys instead, so they can't set it too.
+    #[serde(default)]
+    pub motion: Option<Motion>,
+    /// Animated properties, applied by `set_frame`
+    #[serde(default)]
+    pub keyframes: Vec<ObjectKey>,
+}
+
+/// Values of an object's animated properties at one frame. Properties that are left out
+/// are interpolated between the keys that set them.
+#[derive(Deserialize)]
+pub struct ObjectKey {
+    pub frame: f32,
+    pub transform: Option<Transform>,
+    pub albedo: Option<Vec3>,
+    pub emission: Option<Vec3>,
+    pub metalness: Option<f32>,
+    pub roughness: Option<f32>,
+}
+
+impl Keyframe for ObjectKey {
+    fn frame(&self) -> f32 {
+        self.frame
+    }
+}
+
+impl Object {
+    /// Transform from object to world space at `time`, or `None` if the geometry is
+    /// already in world space.
+    fn transform_at(&self, time: f32) -> Option<Affine> {
+        match (&self.motion, &self.transform) {
+            (Some(motion), Some(transform)) => Some(motion.at(time).compose(transform)),
+            (Some(motion), None) => Some(motion.at(time)),
+            (None, Some(transform)) => Some(transform.clone()),
+            (None, None) => None,
+        }
+    }
+
+    /// Whether any of the object's keys move it, so that its bounds change between frames.
+    pub fn has_transform_keys(&self) -> bool {
+        self.keyframes.iter().any(|k| k.transform.is_some())
+    }
+
+    /// Poses the object as it is at `frame`. Properties without keys keep their value.
+    pub fn set_frame(&mut self, frame: f32) {
+        let keys = &self.keyframes;
+        if let Some(start) = interpolate(keys, frame, |k| k.transform.as_ref()) {
+            // Time 1 of this frame is time 0 of the next
+            let end = interpolate(keys, frame + 1.0, |k| k.transform.as_ref()).unwrap();
+            self.motion = Some(Motion { start, end });
+        }
+        let material = &mut self.material;
+        if let Some(albedo) = interpolate(keys, frame, |k| k.albedo.as_ref()) {
+            material.albedo = ColorTexture::solid(albedo);
+        }
+        if let Some(emission) = interpolate(keys, frame, |k| k.emission.as_ref()) {
+            material.emission = ColorTexture::solid(emission);
+        }
+        if let Some(metalness) = interpolate(keys, frame, |k| k.metalness.as_ref()) {
+            material.metalness = GrayScaleTexture::Solid(metalness);
+        }
+        if let Some(roughness) = interpolate(keys, frame, |k| k.roughness.as_ref()) {
+            material.roughness = GrayScaleTexture::Solid(roughness);
+        }
+    }
 }
 
 pub struct TraceResult<'a> {
     pub hit: RayHit,
-    pub material: &'a Material,
+    pub object: &'a Object,
+    /// Position of `object` among the scene's objects
+    pub index: usize,
 }
 
 impl Traceable for Object {
-    fn trace(&self, ray: &Ray, min: f32, max: f32) -> Option<TraceResult> {
-        self.geometry
-            .intersection(ray, min, max)
-            .map(|hit| TraceResult {
-                hit,
-                material: &self.material,
-            })
+    fn trace(&self, ray: &Ray, min: f32, max: f32) -> Option<TraceResult<'_>> {
+        let hit = match self.transform_at(ray.time) {
+            Some(transform) => {
+                let hit = self
+                    .geometry
+                    .intersection(&transform.to_local(ray), min, max)?;
+                RayHit {
+                    point: ray.point_at(hit.t),
+                    normal: transform.normal(&hit.normal),
+                    geometric_normal: transform.normal(&hit.geometric_normal),
+                    ..hit
+                }
+            }
+            None => self.geometry.intersection(ray, min, max)?,
+        };
+        // Scenes fill in where the object is in their list
+        Some(TraceResult {
+            hit,
+            object: self,
+            index: 0,
+        })
+    }
+
+    fn occluded(&self, ray: &Ray, min: f32, max: f32) -> bool {
+        match self.transform_at(ray.time) {
+            Some(transform) => self.geometry.occluded(&transform.to_local(ray), min, max),
+            None => self.geometry.occluded(ray, min, max),
+        }
+    }
+}
+
+impl Emitter for Object {
+    fn sample_towards(&self, origin: &Vec3, time: f32, u: Vec2) -> Option<SurfaceSample> {
+        let transform = match self.transform_at(time) {
+            Some(transform) => transform,
+            None => return self.geometry.sample_towards(origin, time, u),
+        };
+        let local_origin = transform.inverse_point(origin);
+        let local = self.geometry.sample_towards(&local_origin, time, u)?;
+        let point = transform.point(&local.point);
+        let normal = transform.normal(&local.normal);
+        let pdf = transform.solid_angle_pdf(
+            local.pdf,
+            &local_origin,
+            (&local.point, &local.normal),
+            origin,
+            (&point, &normal),
+        );
+        Some(SurfaceSample {
+            point,
+            normal,
+            uv: local.uv,
+            pdf,
+        })
+    }
+
+    fn pdf_towards(&self, origin: &Vec3, hit: &RayHit) -> f32 {
+        let transform = match self.transform_at(hit.time) {
+            Some(transform) => transform,
+            None => return self.geometry.pdf_towards(origin, hit),
+        };
+        let local_origin = transform.inverse_point(origin);
+        let local = RayHit {
+            point: transform.inverse_point(&hit.point),
+            normal: transform.inverse_normal(&hit.normal),
+            geometric_normal: transform.inverse_normal(&hit.geometric_normal),
+            ..*hit
+        };
+        let pdf = self.geometry.pdf_towards(&local_origin, &local);
+        transform.solid_angle_pdf(
+            pdf,
+            &local_origin,
+            (&local.point, &local.geometric_normal),
+            origin,
+            (&hit.point, &hit.geometric_normal),
+        )
+    }
+}
+
+impl Bounds for Object {
+    /// Bounds covering the object over the whole frame.
+    fn bounds(&self) -> AABB {
+        let mut local = self.geometry.bounds();
+        if let Some(transform) = &self.transform {
+            local = transform.bounds(&local);
+        }
+        match &self.motion {
+            Some(motion) => motion.bounds(&local),
+            None => local,
+        }
     }
 }
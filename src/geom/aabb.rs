@@ -1,6 +1,7 @@
 use crate::ray::Ray;
 use crate::vec::{self, glm, Vec3};
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Clone)]
 pub struct AABB {
     pub min: Vec3,
@@ -9,24 +10,33 @@ pub struct AABB {
 
 pub trait Bounds {
     fn bounds(&self) -> AABB;
+
+    /// Bounds of the part of the item inside `cell`.
+    fn clipped_bounds(&self, cell: &AABB) -> AABB {
+        self.bounds().intersection(cell)
+    }
 }
 
 impl AABB {
-    pub fn intersects(&self, r: &Ray) -> bool {
-        let tx1 = (self.min.x - r.origin.x) * r.inv_dir.x;
-        let tx2 = (self.max.x - r.origin.x) * r.inv_dir.x;
-        let ty1 = (self.min.y - r.origin.y) * r.inv_dir.y;
-        let ty2 = (self.max.y - r.origin.y) * r.inv_dir.y;
-        let tz1 = (self.min.z - r.origin.z) * r.inv_dir.z;
-        let tz2 = (self.max.z - r.origin.z) * r.inv_dir.z;
-
-        let (txmin, txmax) = (f32::min(tx1, tx2), f32::max(tx1, tx2));
-        let (tymin, tymax) = (f32::min(ty1, ty2), f32::max(ty1, ty2));
-        let (tzmin, tzmax) = (f32::min(tz1, tz2), f32::max(tz1, tz2));
-        let tmin = f32::max(f32::max(txmin, tymin), tzmin);
-        let tmax = f32::min(f32::min(txmax, tymax), tzmax);
+    /// Distance along `r` at which it enters the box, if it passes through it anywhere
+    /// between `min` and `max`. Rays starting inside enter at `min`.
+    pub fn entry(&self, r: &Ray, min: f32, max: f32) -> Option<f32> {
+        self.clip(r, min, max).map(|(entry, _)| entry)
+    }
 
-        tmax >= 0.0 && tmin <= tmax
+    /// Distances along `r` at which it enters and leaves the box, limited to `min` and `max`.
+    pub fn clip(&self, r: &Ray, min: f32, max: f32) -> Option<(f32, f32)> {
+        let t1 = (self.min - r.origin).component_mul(&r.inv_dir);
+        let t2 = (self.max - r.origin).component_mul(&r.inv_dir);
+        let near = glm::min2(&t1, &t2);
+        let far = glm::max2(&t1, &t2);
+        let tmin = f32::max(f32::max(near.x, near.y), f32::max(near.z, min));
+        let tmax = f32::min(f32::min(far.x, far.y), f32::min(far.z, max));
+        if tmin <= tmax {
+            Some((tmin, tmax))
+        } else {
+            None
+        }
     }
 
     pub fn surface_area(&self) -> f32 {
@@ -42,6 +52,27 @@ impl AABB {
         AABB { min, max }
     }
 
+    pub fn intersection(&self, other: &AABB) -> AABB {
+        AABB {
+            min: glm::max2(&self.min, &other.min),
+            max: glm::min2(&self.max, &other.max),
+        }
+    }
+
+    pub fn corners(&self) -> [Vec3; 8] {
+        let (a, b) = (&self.min, &self.max);
+        [
+            glm::vec3(a.x, a.y, a.z),
+            glm::vec3(b.x, a.y, a.z),
+            glm::vec3(a.x, b.y, a.z),
+            glm::vec3(b.x, b.y, a.z),
+            glm::vec3(a.x, a.y, b.z),
+            glm::vec3(b.x, a.y, b.z),
+            glm::vec3(a.x, b.y, b.z),
+            glm::vec3(b.x, b.y, b.z),
+        ]
+    }
+
     pub fn split_dimension(&self, x: f32, dimension: usize) -> (AABB, AABB) {
         let mut left_max = self.max;
         left_max.data[dimension] = x;
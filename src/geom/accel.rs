@@ -0,0 +1,189 @@
+use serde::Deserialize;
+
+use super::*;
+use crate::ray::Ray;
+
+/// Kind of acceleration structure built over a set of triangles or objects.
+#[derive(Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum AccelType {
+    #[default]
+    KdTree,
+    Bvh,
+}
+
+impl AccelType {
+    pub fn build<T: Bounds + Sync>(self, items: Vec<T>, kd_tree: &KdTreeConfig) -> Accel<T> {
+        self.build_batched(items, kd_tree, 1)
+    }
+
+    /// Builds a structure whose leaves will test their items `batch` at a time,
+    /// see `KdTree::with_batch`.
+    pub fn build_batched<T: Bounds + Sync>(
+        self,
+        items: Vec<T>,
+        kd_tree: &KdTreeConfig,
+        batch: usize,
+    ) -> Accel<T> {
+        match self {
+            AccelType::KdTree => Accel::KdTree(KdTree::with_batch(items, kd_tree, batch)),
+            AccelType::Bvh => Accel::Bvh(Bvh::with_batch(items, batch)),
+        }
+    }
+}
+
+pub enum Accel<T> {
+    KdTree(KdTree<T>),
+    Bvh(Bvh<T>),
+}
+
+impl<T> Accel<T> {
+    pub fn items(&self) -> &[T] {
+        match self {
+            Accel::KdTree(tree) => tree.items(),
+            Accel::Bvh(bvh) => bvh.items(),
+        }
+    }
+
+    /// The same structure with the items of every leaf replaced, see `KdTree::map_leaves`.
+    pub fn map_leaves<U, F: FnMut(&[u32]) -> Vec<U>>(&self, f: F) -> Accel<U> {
+        match self {
+            Accel::KdTree(tree) => Accel::KdTree(tree.map_leaves(f)),
+            Accel::Bvh(bvh) => Accel::Bvh(bvh.map_leaves(f)),
+        }
+    }
+
+    /// Closest hit along `r` between `min` and `max`, see `KdTree::traverse`.
+    pub fn traverse<'a, H, F>(&'a self, r: &Ray, min: f32, max: f32, intersect: &mut F) -> Option<H>
+    where
+        F: FnMut(&'a T, f32, f32) -> Option<(f32, H)>,
+    {
+        match self {
+            Accel::KdTree(tree) => tree.traverse(r, min, max, intersect),
+            Accel::Bvh(bvh) => bvh.traverse(r, min, max, intersect),
+        }
+    }
+
+    /// Whether `hits` holds for any item along `r`, see `KdTree::any`.
+    pub fn any<F: FnMut(&T, f32, f32) -> bool>(
+        &self,
+        r: &Ray,
+        min: f32,
+        max: f32,
+        hits: &mut F,
+    ) -> bool {
+        match self {
+            Accel::KdTree(tree) => tree.any(r, min, max, hits),
+            Accel::Bvh(bvh) => bvh.any(r, min, max, hits),
+        }
+    }
+}
+
+impl<T: Geometry> Geometry for Accel<T> {
+    fn intersection(&self, r: &Ray, min: f32, max: f32) -> Option<RayHit> {
+        match self {
+            Accel::KdTree(tree) => tree.intersection(r, min, max),
+            Accel::Bvh(bvh) => bvh.intersection(r, min, max),
+        }
+    }
+
+    fn occluded(&self, r: &Ray, min: f32, max: f32) -> bool {
+        match self {
+            Accel::KdTree(tree) => tree.occluded(r, min, max),
+            Accel::Bvh(bvh) => bvh.occluded(r, min, max),
+        }
+    }
+}
+
+impl<T> Bounds for Accel<T> {
+    fn bounds(&self) -> AABB {
+        match self {
+            Accel::KdTree(tree) => tree.bounds(),
+            Accel::Bvh(bvh) => bvh.bounds(),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use rand::Rng as _;
+
+    use super::*;
+    use crate::rng::CounterRng;
+    use crate::vec::{glm, Vec3};
+
+    fn random_point(rng: &mut CounterRng, extent: f32) -> Vec3 {
+        glm::vec3(rng.gen(), rng.gen(), rng.gen()).map(|x: f32| (x * 2.0 - 1.0) * extent)
+    }
+
+    /// Small triangles scattered over a cube, facing every way.
+    pub fn random_triangles(count: usize) -> Vec<Triangle> {
+        let mut rng = CounterRng::new(1, 2, 3);
+        (0..count)
+            .map(|_| {
+                let center = random_point(&mut rng, 3.0);
+                let mut vertex = || Vertex {
+                    pos: center + random_point(&mut rng, 0.8),
+                    normal: random_point(&mut rng, 1.0),
+                    uv: glm::vec2(rng.gen(), rng.gen()),
+                };
+                Triangle::new(vertex(), vertex(), vertex())
+            })
+            .collect()
+    }
+
+    /// Rays from outside and inside the cube of `random_triangles`.
+    pub fn random_rays(count: usize) -> Vec<Ray> {
+        let mut rng = CounterRng::new(4, 5, 6);
+        (0..count)
+            .map(|i| {
+                let origin = random_point(&mut rng, if i % 2 == 0 { 10.0 } else { 2.0 });
+                let target = random_point(&mut rng, 3.0);
+                Ray::new(origin, target - origin)
+            })
+            .collect()
+    }
+
+    pub fn closest(items: &[Triangle], ray: &Ray) -> Option<f32> {
+        items
+            .iter()
+            .filter_map(|tri| tri.intersection(ray, 0.0, f32::MAX))
+            .map(|hit| hit.t)
+            .min_by(f32::total_cmp)
+    }
+
+    pub fn assert_matches_brute_force<G: Geometry>(accel: &G, items: &[Triangle], rays: &[Ray]) {
+        let mut hits = 0;
+        for ray in rays {
+            let expected = closest(items, ray);
+            assert_eq!(
+                accel.intersection(ray, 0.0, f32::MAX).map(|h| h.t),
+                expected
+            );
+            assert_eq!(accel.occluded(ray, 0.0, f32::MAX), expected.is_some());
+            if let Some(t) = expected {
+                hits += 1;
+                assert!(!accel.occluded(ray, 0.0, t * 0.999));
+                assert!(accel.occluded(ray, t * 0.999, t * 1.001));
+            }
+        }
+        assert!(hits > rays.len() / 10);
+    }
+
+    #[test]
+    fn kd_tree_and_bvh_find_the_same_hits() {
+        let items = random_triangles(200);
+        let rays = random_rays(500);
+        let deep = KdTreeConfig {
+            leaf_size: 4,
+            max_depth: 30,
+            ..KdTreeConfig::default()
+        };
+        for config in &[KdTreeConfig::default(), deep] {
+            for &accel in &[AccelType::KdTree, AccelType::Bvh] {
+                let tree: Accel<Triangle> = accel.build(items.clone(), config);
+                assert_matches_brute_force(&tree, &items, &rays);
+            }
+        }
+    }
+}
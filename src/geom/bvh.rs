@@ -0,0 +1,507 @@
+use std::io::{self, Read, Write};
+
+use crate::ray::Ray;
+use crate::vec::Vec3;
+
+use super::aabb::*;
+use super::cache::Binary;
+use super::{Geometry, RayHit};
+
+/// Bounding volume hierarchy built with the surface area heuristic over binned centroids.
+///
+/// Every item is stored exactly once, and the nodes are laid out depth first in a single
+/// array, so the first child of an interior node always directly follows it.
+pub struct Bvh<T> {
+    nodes: Vec<Node>,
+    items: Vec<T>,
+}
+
+#[derive(Clone)]
+struct Node {
+    bounds: AABB,
+    /// Index of the first item for leaves, or of the second child for interior nodes
+    offset: u32,
+    /// Number of items in a leaf, or 0 for interior nodes
+    count: u32,
+}
+
+/// An item's bounds and centroid, along with its position in the input.
+struct Primitive {
+    index: usize,
+    bounds: AABB,
+    centroid: Vec3,
+}
+
+#[derive(Clone, Default)]
+struct Bin {
+    bounds: Option<AABB>,
+    count: usize,
+}
+
+const BINS: usize = 16;
+const MAX_LEAF_SIZE: usize = 4;
+/// Deeper nodes become leaves, which bounds the size of the traversal stack
+const MAX_DEPTH: usize = 64;
+const TRAVERSAL_COST: f32 = 1.0;
+const INTERSECT_COST: f32 = 2.0;
+
+impl<T: Bounds> Bvh<T> {
+    pub fn new(items: Vec<T>) -> Self {
+        Self::with_batch(items, 1)
+    }
+
+    /// Builds a hierarchy whose leaves will test their items `batch` at a time, each batch
+    /// costing as much as a single item, see `map_leaves`.
+    pub fn with_batch(items: Vec<T>, batch: usize) -> Self {
+        let mut prims = items
+            .iter()
+            .enumerate()
+            .map(|(index, item)| {
+                let bounds = item.bounds();
+                let centroid = (bounds.min + bounds.max) * 0.5;
+                Primitive {
+                    index,
+                    bounds,
+                    centroid,
+                }
+            })
+            .collect::<Vec<_>>();
+        let mut nodes = Vec::with_capacity(2 * items.len());
+        build(&mut prims, 0, 0, batch, &mut nodes);
+
+        // Store the items in the order the leaves refer to them
+        let mut items = items.into_iter().map(Some).collect::<Vec<_>>();
+        let items = prims
+            .iter()
+            .map(|prim| items[prim.index].take().expect("Item stored twice"))
+            .collect();
+        Bvh { nodes, items }
+    }
+}
+
+fn union(a: Option<AABB>, b: &AABB) -> Option<AABB> {
+    Some(match a {
+        Some(a) => a.union(b),
+        None => b.clone(),
+    })
+}
+
+/// Builds the subtree over `prims`, which start at `first` in the final item order,
+/// and returns the index of its root. Leaves test their items `batch` at a time.
+fn build(
+    prims: &mut [Primitive],
+    first: usize,
+    depth: usize,
+    batch: usize,
+    nodes: &mut Vec<Node>,
+) -> u32 {
+    let bounds = prims
+        .iter()
+        .fold(None, |acc, prim| union(acc, &prim.bounds))
+        .unwrap_or_default();
+    let index = nodes.len();
+    nodes.push(Node {
+        bounds: bounds.clone(),
+        offset: first as u32,
+        count: prims.len() as u32,
+    });
+    if prims.len() <= 1 || depth >= MAX_DEPTH {
+        return index as u32;
+    }
+
+    let centroids = AABB::from(prims.iter().map(|prim| &prim.centroid));
+    let extent = centroids.max - centroids.min;
+    let axis = if extent.x > extent.y && extent.x > extent.z {
+        0
+    } else if extent.y > extent.z {
+        1
+    } else {
+        2
+    };
+    if extent[axis] <= 0.0 {
+        // Every centroid is in the same spot, no split can separate them
+        return index as u32;
+    }
+    let bin_of = |prim: &Primitive| {
+        let offset = (prim.centroid[axis] - centroids.min[axis]) / extent[axis];
+        usize::min((offset * BINS as f32) as usize, BINS - 1)
+    };
+
+    let mut bins = vec![Bin::default(); BINS];
+    for prim in prims.iter() {
+        let bin = &mut bins[bin_of(prim)];
+        bin.bounds = union(bin.bounds.take(), &prim.bounds);
+        bin.count += 1;
+    }
+    let left = sweep(bins.iter(), batch);
+    let mut right = sweep(bins.iter().rev(), batch);
+    right.reverse();
+    let (split, cost) = left
+        .iter()
+        .zip(right.iter())
+        .map(|(l, r)| TRAVERSAL_COST + INTERSECT_COST * (l + r) / bounds.surface_area())
+        .enumerate()
+        .min_by(|a, b| a.1.partial_cmp(&b.1).expect("Tried to compare NaN"))
+        .expect("No bins to split between");
+    let leaf_cost = INTERSECT_COST * tests(prims.len(), batch);
+    if cost >= leaf_cost && prims.len() <= MAX_LEAF_SIZE {
+        return index as u32;
+    }
+
+    let mut mid = partition(prims, |prim| bin_of(prim) <= split);
+    if mid == 0 || mid == prims.len() {
+        mid = prims.len() / 2;
+    }
+    let (left, right) = prims.split_at_mut(mid);
+    build(left, first, depth + 1, batch, nodes);
+    let second = build(right, first + mid, depth + 1, batch, nodes);
+    nodes[index].offset = second;
+    nodes[index].count = 0;
+    index as u32
+}
+
+/// Number of intersection tests a leaf with `count` items makes.
+fn tests(count: usize, batch: usize) -> f32 {
+    count.div_ceil(batch) as f32
+}
+
+/// Area times intersection tests of the bins on one side of every split between `bins`,
+/// swept from the first one.
+fn sweep<'a, I: Iterator<Item = &'a Bin>>(bins: I, batch: usize) -> Vec<f32> {
+    let mut acc = Bin::default();
+    bins.take(BINS - 1)
+        .map(|bin| {
+            if let Some(bounds) = &bin.bounds {
+                acc.bounds = union(acc.bounds.take(), bounds);
+            }
+            acc.count += bin.count;
+            let area = acc.bounds.as_ref().map_or(0.0, AABB::surface_area);
+            area * tests(acc.count, batch)
+        })
+        .collect()
+}
+
+/// Moves the elements matching `pred` to the front, returning how many there are.
+fn partition<P, F: Fn(&P) -> bool>(items: &mut [P], pred: F) -> usize {
+    let mut mid = 0;
+    for i in 0..items.len() {
+        if pred(&items[i]) {
+            items.swap(i, mid);
+            mid += 1;
+        }
+    }
+    mid
+}
+
+impl<T> Bvh<T> {
+    /// Every item in the hierarchy, in the order the leaves refer to them.
+    pub fn items(&self) -> &[T] {
+        &self.items
+    }
+
+    /// The same hierarchy with the items of every leaf replaced by the ones `f` makes
+    /// from their indices, which must be at least one for every leaf.
+    pub fn map_leaves<U, F: FnMut(&[u32]) -> Vec<U>>(&self, mut f: F) -> Bvh<U> {
+        let mut items = Vec::new();
+        let nodes = self
+            .nodes
+            .iter()
+            .map(|node| {
+                if node.count == 0 || self.items.is_empty() {
+                    return node.clone();
+                }
+                let indices = (node.offset..node.offset + node.count).collect::<Vec<_>>();
+                let first = items.len();
+                items.extend(f(&indices));
+                assert!(items.len() > first, "Leaf left without items");
+                Node {
+                    bounds: node.bounds.clone(),
+                    offset: first as u32,
+                    count: (items.len() - first) as u32,
+                }
+            })
+            .collect();
+        Bvh { nodes, items }
+    }
+
+    /// Closest hit along `r` between `min` and `max` among the items in the leaves it passes
+    /// through, visiting nearer nodes first. `intersect` tests a single item between the
+    /// given distances, returning the distance to the hit along with it.
+    pub fn traverse<'a, H, F>(&'a self, r: &Ray, min: f32, max: f32, intersect: &mut F) -> Option<H>
+    where
+        F: FnMut(&'a T, f32, f32) -> Option<(f32, H)>,
+    {
+        let mut max = max;
+        let mut result = None;
+        // Nodes still to visit along with the distance at which the ray enters them
+        let mut stack = [(0, 0.0); MAX_DEPTH + 1];
+        let mut len = 0;
+        if self.items.is_empty() || self.nodes[0].bounds.entry(r, min, max).is_none() {
+            return None;
+        }
+        let mut current = 0;
+        loop {
+            let node = &self.nodes[current];
+            let next = if node.count > 0 {
+                let start = node.offset as usize;
+                for item in &self.items[start..start + node.count as usize] {
+                    if let Some((t, hit)) = intersect(item, min, max) {
+                        max = t;
+                        result = Some(hit);
+                    }
+                }
+                None
+            } else {
+                let (first, second) = (current + 1, node.offset as usize);
+                let t_first = self.nodes[first].bounds.entry(r, min, max);
+                let t_second = self.nodes[second].bounds.entry(r, min, max);
+                match (t_first, t_second) {
+                    (Some(a), Some(b)) => {
+                        let (near, far, t_far) = if a <= b {
+                            (first, second, b)
+                        } else {
+                            (second, first, a)
+                        };
+                        stack[len] = (far, t_far);
+                        len += 1;
+                        Some(near)
+                    }
+                    (Some(_), None) => Some(first),
+                    (None, Some(_)) => Some(second),
+                    (None, None) => None,
+                }
+            };
+            current = match next {
+                Some(next) => next,
+                None => loop {
+                    if len == 0 {
+                        return result;
+                    }
+                    len -= 1;
+                    let (node, t) = stack[len];
+                    // Skip nodes that start behind the closest hit found since pushing them
+                    if t <= max {
+                        break node;
+                    }
+                },
+            };
+        }
+    }
+
+    /// Whether `hits` holds for any of the items in the leaves `r` passes through between
+    /// `min` and `max`, stopping at the first one it holds for.
+    pub fn any<F: FnMut(&T, f32, f32) -> bool>(
+        &self,
+        r: &Ray,
+        min: f32,
+        max: f32,
+        hits: &mut F,
+    ) -> bool {
+        if self.items.is_empty() {
+            return false;
+        }
+        // Second children still to visit, the first one is always visited right away
+        let mut stack = [0; MAX_DEPTH + 1];
+        let mut len = 0;
+        let mut current = 0;
+        loop {
+            let node = &self.nodes[current];
+            let next = if node.bounds.entry(r, min, max).is_none() {
+                None
+            } else if node.count > 0 {
+                let start = node.offset as usize;
+                let items = &self.items[start..start + node.count as usize];
+                if items.iter().any(|item| hits(item, min, max)) {
+                    return true;
+                }
+                None
+            } else {
+                stack[len] = node.offset as usize;
+                len += 1;
+                Some(current + 1)
+            };
+            current = match next {
+                Some(next) => next,
+                None if len > 0 => {
+                    len -= 1;
+                    stack[len]
+                }
+                None => return false,
+            };
+        }
+    }
+}
+
+impl<T: Geometry> Geometry for Bvh<T> {
+    fn intersection(&self, r: &Ray, min: f32, max: f32) -> Option<RayHit> {
+        self.traverse(r, min, max, &mut |tri, min, max| {
+            tri.intersection(r, min, max).map(|hit| (hit.t, hit))
+        })
+    }
+
+    fn occluded(&self, r: &Ray, min: f32, max: f32) -> bool {
+        self.any(r, min, max, &mut |tri, min, max| tri.occluded(r, min, max))
+    }
+}
+
+impl<T: Binary> Binary for Bvh<T> {
+    fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.items.write(w)?;
+        self.nodes.write(w)
+    }
+
+    fn read<R: Read>(r: &mut R) -> io::Result<Self> {
+        let bvh = Bvh {
+            items: Vec::read(r)?,
+            nodes: Vec::read(r)?,
+        };
+        if !bvh.is_valid() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid BVH"));
+        }
+        Ok(bvh)
+    }
+}
+
+impl<T> Bvh<T> {
+    /// Whether the nodes form a tree that traversal can walk without going out of bounds
+    /// or overflowing its stack: every leaf refers to items that exist, and every
+    /// interior node's children come after it, are visited once and are not too deep.
+    fn is_valid(&self) -> bool {
+        if self.nodes.is_empty() {
+            return false;
+        }
+        if self.items.is_empty() {
+            // Traversal stops before looking at any node
+            return true;
+        }
+        let mut visited = vec![false; self.nodes.len()];
+        let mut stack = vec![(0, 0)];
+        while let Some((index, depth)) = stack.pop() {
+            if std::mem::replace(&mut visited[index], true) {
+                return false;
+            }
+            let node = &self.nodes[index];
+            if node.count > 0 {
+                if node.offset as usize + node.count as usize > self.items.len() {
+                    return false;
+                }
+            } else {
+                let (first, second) = (index + 1, node.offset as usize);
+                if depth >= MAX_DEPTH || second <= first || second >= self.nodes.len() {
+                    return false;
+                }
+                stack.push((first, depth + 1));
+                stack.push((second, depth + 1));
+            }
+        }
+        true
+    }
+}
+
+impl Binary for Node {
+    fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.bounds.write(w)?;
+        self.offset.write(w)?;
+        self.count.write(w)
+    }
+
+    fn read<R: Read>(r: &mut R) -> io::Result<Self> {
+        Ok(Node {
+            bounds: AABB::read(r)?,
+            offset: u32::read(r)?,
+            count: u32::read(r)?,
+        })
+    }
+}
+
+impl<T> Bounds for Bvh<T> {
+    fn bounds(&self) -> AABB {
+        self.nodes[0].bounds.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vec::glm;
+
+    fn bvh() -> Bvh<AABB> {
+        let items = (0..20)
+            .map(|i| AABB {
+                min: glm::vec3(i as f32, (i % 3) as f32, 0.0),
+                max: glm::vec3(i as f32 + 0.5, (i % 3) as f32 + 1.0, 1.0),
+            })
+            .collect();
+        Bvh::new(items)
+    }
+
+    fn round_trip(bvh: &Bvh<AABB>) -> io::Result<Bvh<AABB>> {
+        let mut bytes = Vec::new();
+        bvh.write(&mut bytes)?;
+        Bvh::read(&mut bytes.as_slice())
+    }
+
+    #[test]
+    fn reads_back_what_it_writes() {
+        let bvh = bvh();
+        assert!(bvh.nodes.len() > 1);
+        let read = round_trip(&bvh).unwrap();
+        assert_eq!(read.nodes.len(), bvh.nodes.len());
+        assert_eq!(read.items().len(), bvh.items().len());
+    }
+
+    #[test]
+    fn rejects_leaves_past_the_items() {
+        let mut bvh = bvh();
+        let leaf = bvh.nodes.iter_mut().find(|n| n.count > 0).unwrap();
+        leaf.offset = 20;
+        assert!(round_trip(&bvh).is_err());
+    }
+
+    #[test]
+    fn rejects_children_out_of_order() {
+        let mut bvh = bvh();
+        // The second child pointing back at its parent would loop forever
+        bvh.nodes[0].offset = 0;
+        assert!(round_trip(&bvh).is_err());
+        let mut bvh = self::bvh();
+        bvh.nodes[0].offset = bvh.nodes.len() as u32;
+        assert!(round_trip(&bvh).is_err());
+    }
+
+    #[test]
+    fn batches_make_splitting_leaves_less_worth_it() {
+        let items = || {
+            (0..4)
+                .map(|i| AABB {
+                    min: glm::vec3(i as f32 * 2.0, 0.0, 0.0),
+                    max: glm::vec3(i as f32 * 2.0 + 1.0, 1.0, 1.0),
+                })
+                .collect::<Vec<_>>()
+        };
+        assert!(Bvh::new(items()).nodes.len() > 1);
+        assert_eq!(Bvh::with_batch(items(), 4).nodes.len(), 1);
+    }
+
+    #[test]
+    fn maps_the_items_of_every_leaf() {
+        let bvh = bvh();
+        // Two new items for each old one, so the leaves grow
+        let mapped = bvh.map_leaves(|indices| {
+            indices
+                .iter()
+                .flat_map(|&i| vec![bvh.items()[i as usize].clone(); 2])
+                .collect()
+        });
+        assert!(mapped.is_valid());
+        assert_eq!(mapped.items().len(), 2 * bvh.items().len());
+        for (node, old) in mapped.nodes.iter().zip(&bvh.nodes) {
+            assert_eq!(node.count, 2 * old.count);
+            for i in 0..node.count {
+                let item = &mapped.items()[(node.offset + i) as usize];
+                let old_item = &bvh.items()[(old.offset + i / 2) as usize];
+                assert_eq!((item.min, item.max), (old_item.min, old_item.max));
+            }
+        }
+    }
+}
@@ -0,0 +1,343 @@
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use super::*;
+use crate::hash::fnv1a;
+use crate::vec::{glm, Vec2, Vec3};
+
+const MAGIC: &[u8; 8] = b"PRAYMSH4";
+
+/// Values that can be written to and read back from a cache file.
+pub trait Binary: Sized {
+    fn write<W: Write>(&self, w: &mut W) -> io::Result<()>;
+    fn read<R: Read>(r: &mut R) -> io::Result<Self>;
+}
+
+/// What a cache file must have been written for to be valid: the contents of the source
+/// file and every setting that affects the built tree.
+#[derive(PartialEq)]
+pub struct Key {
+    source_hash: u64,
+    accel: u8,
+    params: Vec<f32>,
+}
+
+impl Key {
+    pub fn new(source: &[u8], accel: AccelType, kd_tree: &KdTreeConfig) -> Self {
+        let (accel, params) = match accel {
+            AccelType::KdTree => (
+                0,
+                vec![
+                    kd_tree.traversal_cost,
+                    kd_tree.intersect_cost,
+                    kd_tree.max_depth as f32,
+                    kd_tree.leaf_size as f32,
+                ],
+            ),
+            AccelType::Bvh => (1, Vec::new()),
+        };
+        Key {
+            source_hash: fnv1a(source),
+            accel,
+            params,
+        }
+    }
+}
+
+impl Binary for Key {
+    fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(&self.source_hash.to_le_bytes())?;
+        w.write_all(&[self.accel])?;
+        self.params.write(w)
+    }
+
+    fn read<R: Read>(r: &mut R) -> io::Result<Self> {
+        let mut hash = [0; 8];
+        r.read_exact(&mut hash)?;
+        let mut accel = [0; 1];
+        r.read_exact(&mut accel)?;
+        Ok(Key {
+            source_hash: u64::from_le_bytes(hash),
+            accel: accel[0],
+            params: Vec::read(r)?,
+        })
+    }
+}
+
+impl Key {
+    /// Hash of everything in the key, which tells apart the files of the same mesh
+    /// built with different settings.
+    fn hash(&self) -> u64 {
+        let mut bytes = Vec::new();
+        self.write(&mut bytes)
+            .expect("Writing to memory can't fail");
+        fnv1a(&bytes)
+    }
+}
+
+/// Cache file for the mesh at `source` built for `key`,
+/// e.g. `bunny.obj.0123456789abcdef.cache` for `bunny.obj`.
+pub fn path(source: &Path, key: &Key) -> PathBuf {
+    source.with_extension(format!(
+        "{}.{:016x}.cache",
+        source.extension().and_then(OsStr::to_str).unwrap_or(""),
+        key.hash()
+    ))
+}
+
+/// Removes the caches of the mesh at `source` written for keys other than `key`,
+/// which are left behind whenever the mesh or its build settings change.
+pub fn remove_stale(source: &Path, key: &Key) -> io::Result<()> {
+    let current = path(source, key);
+    let name = current
+        .file_name()
+        .and_then(OsStr::to_str)
+        .unwrap_or_default();
+    let suffix = format!("{:016x}.cache", key.hash());
+    let prefix = name.strip_suffix(&suffix).unwrap_or(name);
+    let dir = match current.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let other = entry.file_name();
+        let hash = other
+            .to_str()
+            .and_then(|other| other.strip_prefix(prefix))
+            .and_then(|rest| rest.strip_suffix(".cache"));
+        let is_cache = hash
+            .is_some_and(|hash| hash.len() == 16 && hash.chars().all(|c| c.is_ascii_hexdigit()));
+        if is_cache && other != name {
+            std::fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads the tree and triangle shading cached at `path`, failing if it was written
+/// for a different `key`.
+pub fn load(path: &Path, key: &Key) -> io::Result<(Accel<TrianglePacket>, Vec<Shading>)> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut magic = [0; 8];
+    file.read_exact(&mut magic)?;
+    if &magic != MAGIC || Key::read(&mut file)? != *key {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Not a cache for this mesh",
+        ));
+    }
+    let tree = Accel::read(&mut file)?;
+    let shading = Vec::<Shading>::read(&mut file)?;
+    if TrianglePacket::locate(tree.items(), shading.len()).is_none() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Cached packets don't match the triangles",
+        ));
+    }
+    Ok((tree, shading))
+}
+
+pub fn save(
+    path: &Path,
+    key: &Key,
+    tree: &Accel<TrianglePacket>,
+    shading: &[Shading],
+) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(MAGIC)?;
+    key.write(&mut file)?;
+    tree.write(&mut file)?;
+    (shading.len() as u32).write(&mut file)?;
+    shading.iter().try_for_each(|s| s.write(&mut file))?;
+    file.flush()
+}
+
+impl Binary for u32 {
+    fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(&self.to_le_bytes())
+    }
+
+    fn read<R: Read>(r: &mut R) -> io::Result<Self> {
+        let mut buf = [0; 4];
+        r.read_exact(&mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+}
+
+impl Binary for f32 {
+    fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(&self.to_le_bytes())
+    }
+
+    fn read<R: Read>(r: &mut R) -> io::Result<Self> {
+        let mut buf = [0; 4];
+        r.read_exact(&mut buf)?;
+        Ok(f32::from_le_bytes(buf))
+    }
+}
+
+impl Binary for Vec2 {
+    fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.x.write(w)?;
+        self.y.write(w)
+    }
+
+    fn read<R: Read>(r: &mut R) -> io::Result<Self> {
+        Ok(glm::vec2(f32::read(r)?, f32::read(r)?))
+    }
+}
+
+impl Binary for Vec3 {
+    fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.x.write(w)?;
+        self.y.write(w)?;
+        self.z.write(w)
+    }
+
+    fn read<R: Read>(r: &mut R) -> io::Result<Self> {
+        Ok(glm::vec3(f32::read(r)?, f32::read(r)?, f32::read(r)?))
+    }
+}
+
+impl<T: Binary> Binary for Vec<T> {
+    fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        (self.len() as u32).write(w)?;
+        self.iter().try_for_each(|item| item.write(w))
+    }
+
+    fn read<R: Read>(r: &mut R) -> io::Result<Self> {
+        let len = u32::read(r)?;
+        (0..len).map(|_| T::read(r)).collect()
+    }
+}
+
+impl Binary for AABB {
+    fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.min.write(w)?;
+        self.max.write(w)
+    }
+
+    fn read<R: Read>(r: &mut R) -> io::Result<Self> {
+        Ok(AABB {
+            min: Vec3::read(r)?,
+            max: Vec3::read(r)?,
+        })
+    }
+}
+
+impl<T: Binary> Binary for Accel<T> {
+    fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        match self {
+            Accel::KdTree(tree) => {
+                0u32.write(w)?;
+                tree.write(w)
+            }
+            Accel::Bvh(bvh) => {
+                1u32.write(w)?;
+                bvh.write(w)
+            }
+        }
+    }
+
+    fn read<R: Read>(r: &mut R) -> io::Result<Self> {
+        match u32::read(r)? {
+            0 => Ok(Accel::KdTree(KdTree::read(r)?)),
+            1 => Ok(Accel::Bvh(Bvh::read(r)?)),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Unknown acceleration structure",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::accel::tests::{random_rays, random_triangles};
+    use super::*;
+
+    #[test]
+    fn trees_round_trip_through_the_cache() {
+        let items = random_triangles(100);
+        let rays = random_rays(200);
+        let config = KdTreeConfig::default();
+        let source = std::env::temp_dir().join("prayer_cache_round_trip_test.obj");
+        for &accel in &[AccelType::KdTree, AccelType::Bvh] {
+            let key = Key::new(b"v 0 0 0", accel, &config);
+            let path = path(&source, &key);
+            let triangles = accel.build_batched(items.clone(), &config, LANES);
+            let tree = triangles.map_leaves(|ids| {
+                TrianglePacket::group(ids, |id| triangles.items()[id as usize].positions())
+            });
+            let shading = triangles
+                .items()
+                .iter()
+                .map(Triangle::shading)
+                .collect::<Vec<_>>();
+            save(&path, &key, &tree, &shading).unwrap();
+            let loaded = load(&path, &key);
+            let stale = load(&path, &Key::new(b"v 0 0 1", accel, &config));
+            save(&path, &key, &tree, &shading[1..]).unwrap();
+            let mismatched = load(&path, &key);
+            std::fs::remove_file(&path).unwrap();
+            let (loaded, loaded_shading) = loaded.unwrap();
+            assert!(stale.is_err());
+            assert!(mismatched.is_err());
+            assert_eq!(loaded_shading.len(), shading.len());
+            for ray in &rays {
+                let lanes = RayLanes::new(ray);
+                let hit = |tree: &Accel<TrianglePacket>| {
+                    tree.traverse(ray, 0.0, f32::MAX, &mut |packet, min, max| {
+                        let (t, lane) = packet.intersect(&lanes, min, max)?;
+                        Some((t, (t, packet.id(lane))))
+                    })
+                };
+                assert_eq!(hit(&loaded), hit(&tree));
+            }
+        }
+    }
+
+    #[test]
+    fn path_depends_on_the_key() {
+        let source = Path::new("meshes/bunny.obj");
+        let config = KdTreeConfig::default();
+        let kd_tree = Key::new(b"v 0 0 0", AccelType::KdTree, &config);
+        let bvh = Key::new(b"v 0 0 0", AccelType::Bvh, &config);
+        let kd_path = path(source, &kd_tree);
+        assert_ne!(kd_path, path(source, &bvh));
+        assert_eq!(kd_path.parent(), source.parent());
+        let name = kd_path.file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with("bunny.obj.") && name.ends_with(".cache"));
+    }
+
+    #[test]
+    fn removes_caches_for_other_keys() {
+        let dir = std::env::temp_dir().join("prayer_cache_stale_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("bunny.obj");
+        let config = KdTreeConfig::default();
+        let old = Key::new(b"v 0 0 0", AccelType::KdTree, &config);
+        let new = Key::new(b"v 0 0 1", AccelType::KdTree, &config);
+        let unrelated = [
+            "bunny.obj",
+            "bunny.obj.notes.cache",
+            "dragon.obj.0123456789abcdef.cache",
+        ];
+        for name in &unrelated {
+            std::fs::write(dir.join(name), b"").unwrap();
+        }
+        std::fs::write(path(&source, &old), b"").unwrap();
+        std::fs::write(path(&source, &new), b"").unwrap();
+        remove_stale(&source, &new).unwrap();
+        let old_exists = path(&source, &old).exists();
+        let new_exists = path(&source, &new).exists();
+        let kept = unrelated.iter().all(|name| dir.join(name).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(!old_exists);
+        assert!(new_exists);
+        assert!(kept);
+    }
+}
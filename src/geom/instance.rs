@@ -0,0 +1,72 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use serde::{Deserialize, Deserializer};
+
+use super::*;
+use crate::ray::Ray;
+use crate::{Vec2, Vec3};
+
+/// One of the scene's named meshes, shared with every other object using the same name
+/// so it is only loaded and built once.
+pub struct Instance {
+    pub name: String,
+    mesh: Option<Arc<Mesh>>,
+}
+
+impl Instance {
+    /// Looks up the mesh the instance refers to among the scene's named meshes.
+    pub fn resolve(&mut self, meshes: &HashMap<String, Arc<Mesh>>) -> Result<(), String> {
+        let mesh = meshes
+            .get(&self.name)
+            .ok_or_else(|| format!("No mesh named {} in the scene", self.name))?;
+        self.mesh = Some(Arc::clone(mesh));
+        Ok(())
+    }
+
+    fn mesh(&self) -> &Mesh {
+        self.mesh
+            .as_ref()
+            .expect("Instance was not resolved to a mesh")
+    }
+}
+
+impl Geometry for Instance {
+    fn intersection(&self, ray: &Ray, min: f32, max: f32) -> Option<RayHit> {
+        self.mesh().intersection(ray, min, max)
+    }
+
+    fn occluded(&self, ray: &Ray, min: f32, max: f32) -> bool {
+        self.mesh().occluded(ray, min, max)
+    }
+}
+
+impl Emitter for Instance {
+    fn sample_towards(&self, origin: &Vec3, time: f32, u: Vec2) -> Option<SurfaceSample> {
+        self.mesh().sample_towards(origin, time, u)
+    }
+
+    fn pdf_towards(&self, origin: &Vec3, hit: &RayHit) -> f32 {
+        self.mesh().pdf_towards(origin, hit)
+    }
+}
+
+impl Bounds for Instance {
+    fn bounds(&self) -> AABB {
+        self.mesh().bounds()
+    }
+}
+
+impl<'de> Deserialize<'de> for Instance {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct InstanceDesc {
+            mesh: String,
+        }
+        let InstanceDesc { mesh } = InstanceDesc::deserialize(deserializer)?;
+        Ok(Instance {
+            name: mesh,
+            mesh: None,
+        })
+    }
+}
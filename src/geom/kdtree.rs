@@ -1,209 +1,576 @@
+use std::io::{self, Read, Write};
+
 use rayon::prelude::*;
+use serde::Deserialize;
 
-use crate::Ray;
+use crate::ray::Ray;
 
 use super::aabb::*;
+use super::cache::Binary;
 use super::{Geometry, RayHit};
 
-pub enum KdTree<T> {
-    Leaf {
-        bounds: AABB,
-        geoms: Vec<T>,
-    },
-    Node {
-        bounds: AABB,
-        left: Box<KdTree<T>>,
-        right: Box<KdTree<T>>,
+/// Kd-tree over items that are stored once, with leaves referring to them by index.
+pub struct KdTree<T> {
+    items: Vec<T>,
+    bounds: AABB,
+    root: Node,
+}
+
+enum Node {
+    Leaf(Vec<u32>),
+    Split {
+        /// Axis and position of the plane separating the children
+        dim: usize,
+        pos: f32,
+        children: Box<[Node; 2]>,
     },
 }
 
+/// Settings for building kd-trees with the surface area heuristic.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct KdTreeConfig {
+    /// Estimated cost of stepping through a node, relative to `intersect_cost`
+    pub traversal_cost: f32,
+    /// Estimated cost of intersecting a ray with a single item
+    pub intersect_cost: f32,
+    /// Depth below which every node is a leaf, or 0 to pick one from the number of items
+    pub max_depth: usize,
+    /// Nodes with at most this many items are not split any further
+    pub leaf_size: usize,
+}
+
+impl Default for KdTreeConfig {
+    fn default() -> Self {
+        KdTreeConfig {
+            traversal_cost: 1.0,
+            intersect_cost: 2.0,
+            max_depth: 0,
+            leaf_size: 1,
+        }
+    }
+}
+
 struct Split {
     dim: usize,
     pos: f32,
     cost: f32,
 }
 
-impl<T: Bounds + Clone + Sync> KdTree<T> {
-    pub fn new(geoms: Vec<T>) -> Self {
-        let bounds = total_bounds(&geoms);
-        Self::build(bounds, geoms)
-    }
-
-    fn build(bounds: AABB, geoms: Vec<T>) -> Self {
-        let cost = cost(&bounds, geoms.len());
-        let splits = (0..3)
-            .into_par_iter()
-            .filter_map(|dim| optimal_split(geoms.iter(), &bounds, dim as usize));
-        let min = splits.min_by(|a, b| a.cost.partial_cmp(&b.cost).expect("Tried to compare NaN"));
-        match &min {
-            Some(split) if split.cost < cost => {
-                let (left, right) = bounds.split_dimension(split.pos, split.dim);
-                let (left_geoms, right_geoms) = partition_dimension(geoms, split.pos, split.dim);
-                KdTree::Node {
-                    bounds,
-                    left: Box::new(KdTree::build(left, left_geoms)),
-                    right: Box::new(KdTree::build(right, right_geoms)),
-                }
-            }
-            _ => KdTree::Leaf { bounds, geoms },
+/// Number of candidate planes per axis is one less than this
+const BINS: usize = 32;
+/// Nodes with more items than this build their children in parallel
+const PARALLEL_ITEMS: usize = 4096;
+
+struct Builder<'a, T> {
+    items: &'a [T],
+    config: &'a KdTreeConfig,
+    max_depth: usize,
+    /// Number of items that leaves test at once for the cost of one
+    batch: usize,
+}
+
+/// An item in a node, along with the bounds of the part of it inside the node.
+struct Ref {
+    index: u32,
+    bounds: AABB,
+}
+
+impl<T: Bounds + Sync> KdTree<T> {
+    pub fn new(items: Vec<T>, config: &KdTreeConfig) -> Self {
+        Self::with_batch(items, config, 1)
+    }
+
+    /// Builds a tree whose leaves will test their items `batch` at a time, each batch
+    /// costing as much as a single item, see `map_leaves`.
+    pub fn with_batch(items: Vec<T>, config: &KdTreeConfig, batch: usize) -> Self {
+        let refs = items
+            .par_iter()
+            .enumerate()
+            .map(|(index, item)| Ref {
+                index: index as u32,
+                bounds: item.bounds(),
+            })
+            .collect::<Vec<_>>();
+        let bounds = refs.iter().skip(1).fold(
+            refs.first().map(|r| r.bounds.clone()).unwrap_or_default(),
+            |a, r| a.union(&r.bounds),
+        );
+        let max_depth = match config.max_depth {
+            // Enough for a balanced tree with an item per leaf, and a few more levels
+            // to cut away empty space
+            0 => 4 + (items.len().max(1) as f32).log2().ceil() as usize,
+            depth => depth,
+        };
+        let builder = Builder {
+            items: &items,
+            config,
+            max_depth,
+            batch,
+        };
+        let root = builder.build(&bounds, refs, 0);
+        KdTree {
+            items,
+            bounds,
+            root,
         }
     }
 }
 
-#[derive(PartialEq, PartialOrd)]
-enum State {
-    Start,
-    End,
+impl<T: Bounds + Sync> Builder<'_, T> {
+    fn build(&self, bounds: &AABB, refs: Vec<Ref>, depth: usize) -> Node {
+        if refs.len() <= self.config.leaf_size || depth >= self.max_depth {
+            return leaf(refs);
+        }
+        let leaf_cost = self.config.intersect_cost * self.tests(refs.len());
+        let split = (0..3)
+            .filter_map(|dim| self.best_split(bounds, &refs, dim))
+            .min_by(|a, b| a.cost.partial_cmp(&b.cost).expect("Tried to compare NaN"));
+        let split = match split {
+            Some(split) if split.cost < leaf_cost => split,
+            _ => return leaf(refs),
+        };
+
+        let (left_bounds, right_bounds) = bounds.split_dimension(split.pos, split.dim);
+        let mut left = Vec::new();
+        let mut right = Vec::new();
+        for r in refs {
+            let (in_left, in_right) = (
+                r.bounds.min[split.dim] <= split.pos,
+                r.bounds.max[split.dim] > split.pos,
+            );
+            if in_left && in_right {
+                // Only keep the part of the item on either side, so it can be split off
+                // from its neighbours further down
+                let item = &self.items[r.index as usize];
+                left.push(Ref {
+                    index: r.index,
+                    bounds: item.clipped_bounds(&left_bounds),
+                });
+                right.push(Ref {
+                    index: r.index,
+                    bounds: item.clipped_bounds(&right_bounds),
+                });
+            } else if in_left {
+                left.push(r);
+            } else {
+                right.push(r);
+            }
+        }
+        let (left, right) = if left.len() + right.len() > PARALLEL_ITEMS {
+            rayon::join(
+                || self.build(&left_bounds, left, depth + 1),
+                || self.build(&right_bounds, right, depth + 1),
+            )
+        } else {
+            (
+                self.build(&left_bounds, left, depth + 1),
+                self.build(&right_bounds, right, depth + 1),
+            )
+        };
+        Node::Split {
+            dim: split.dim,
+            pos: split.pos,
+            children: Box::new([left, right]),
+        }
+    }
+
+    /// Number of intersection tests a leaf with `count` items makes.
+    fn tests(&self, count: usize) -> f32 {
+        count.div_ceil(self.batch) as f32
+    }
+
+    /// Cheapest of the planes between equally sized bins across `bounds` along `dim`.
+    fn best_split(&self, bounds: &AABB, refs: &[Ref], dim: usize) -> Option<Split> {
+        let (lo, hi) = (bounds.min[dim], bounds.max[dim]);
+        let area = bounds.surface_area();
+        if hi <= lo || area <= 0.0 {
+            return None;
+        }
+        let bin = |x: f32| {
+            let offset = (x - lo) / (hi - lo) * BINS as f32;
+            usize::min(f32::max(offset, 0.0) as usize, BINS - 1)
+        };
+        // Number of items starting and ending in every bin
+        let mut starts = [0; BINS];
+        let mut ends = [0; BINS];
+        for r in refs {
+            starts[bin(r.bounds.min[dim])] += 1;
+            ends[bin(r.bounds.max[dim])] += 1;
+        }
+
+        // Surface area of a part of the node extending `x` along `dim`
+        let extent = bounds.max - bounds.min;
+        let (a, b) = (extent[(dim + 1) % 3], extent[(dim + 2) % 3]);
+        let child_area = |x: f32| 2.0 * (a * b + x * (a + b));
+
+        let config = self.config;
+        let (mut left, mut right) = (0, refs.len());
+        (1..BINS)
+            .map(|i| {
+                // Items starting before the plane overlap the left side, and items ending
+                // before it no longer overlap the right one
+                left += starts[i - 1];
+                right -= ends[i - 1];
+                let x = (hi - lo) * i as f32 / BINS as f32;
+                let cost = config.traversal_cost
+                    + config.intersect_cost
+                        * (child_area(x) * self.tests(left)
+                            + child_area(hi - lo - x) * self.tests(right))
+                        / area;
+                Split {
+                    dim,
+                    pos: lo + x,
+                    cost,
+                }
+            })
+            .min_by(|a, b| a.cost.partial_cmp(&b.cost).expect("Tried to compare NaN"))
+    }
 }
 
-#[derive(PartialEq, PartialOrd)]
-struct Marker {
-    pos: f32,
-    state: State,
+fn leaf(refs: Vec<Ref>) -> Node {
+    Node::Leaf(refs.into_iter().map(|r| r.index).collect())
 }
 
-fn cost(aabb: &AABB, num_geoms: usize) -> f32 {
-    const INTERSECT_COST: f32 = 2.0;
-    INTERSECT_COST * aabb.surface_area() * num_geoms as f32
+/// Children of a split that a ray passes through while inside its parent.
+enum Step<'a> {
+    One(&'a Node),
+    /// Near and far child, along with the distance at which the ray crosses between them
+    Both(&'a Node, &'a Node, f32),
 }
 
-fn total_bounds<G: Bounds>(geoms: &[G]) -> AABB {
-    let bounds = geoms.get(0).map(|g| g.bounds()).unwrap_or_default();
-    geoms
-        .iter()
-        .map(Bounds::bounds)
-        .fold(bounds, |a, b| AABB::union(&a, &b))
+fn step<'a>(
+    r: &Ray,
+    dim: usize,
+    pos: f32,
+    children: &'a [Node; 2],
+    tmin: f32,
+    tmax: f32,
+) -> Step<'a> {
+    let origin = r.origin[dim];
+    let t_split = (pos - origin) * r.inv_dir[dim];
+    let left_first = origin < pos || (origin == pos && r.direction[dim] <= 0.0);
+    let [left, right] = children;
+    let (near, far) = if left_first {
+        (left, right)
+    } else {
+        (right, left)
+    };
+    if t_split > tmax || t_split <= 0.0 {
+        Step::One(near)
+    } else if t_split < tmin {
+        Step::One(far)
+    } else {
+        Step::Both(near, far, t_split)
+    }
 }
 
-fn sorted_markers<'a, I, G>(geoms: I, dimension: usize) -> Vec<Marker>
-where
-    I: Iterator<Item = &'a G>,
-    G: Bounds + 'a,
-{
-    let count = geoms.size_hint().1.unwrap_or(0);
-    let mut markers = Vec::with_capacity(count * 2);
-    for bound in geoms.map(Bounds::bounds) {
-        let start = Marker {
-            state: State::Start,
-            pos: bound.min[dimension],
-        };
-        let end = Marker {
-            state: State::End,
-            pos: bound.max[dimension],
+impl<T> KdTree<T> {
+    /// Every item in the tree, each stored once.
+    pub fn items(&self) -> &[T] {
+        &self.items
+    }
+
+    /// The same tree with the items of every leaf replaced by the ones `f` makes from
+    /// their indices. Items in several leaves are passed to `f` once for each of them,
+    /// and the new items are only referred to by the leaf they were made for.
+    pub fn map_leaves<U, F: FnMut(&[u32]) -> Vec<U>>(&self, mut f: F) -> KdTree<U> {
+        let mut items = Vec::new();
+        let root = self.root.map_leaves(&mut items, &mut f);
+        KdTree {
+            items,
+            bounds: self.bounds.clone(),
+            root,
+        }
+    }
+
+    /// Closest hit along `r` between `min` and `max` among the items in the leaves it passes
+    /// through. `intersect` tests a single item between the given distances, returning the
+    /// distance to the hit along with it.
+    ///
+    /// Cells are visited front to back, stopping at the first one that contains a hit.
+    pub fn traverse<'a, H, F>(&'a self, r: &Ray, min: f32, max: f32, intersect: &mut F) -> Option<H>
+    where
+        F: FnMut(&'a T, f32, f32) -> Option<(f32, H)>,
+    {
+        let (mut tmin, mut tmax) = self.bounds.clip(r, min, max)?;
+        let mut max = max;
+        let mut result = None;
+        // Far children still to visit, along with the part of the ray inside them
+        let mut stack = Vec::new();
+        let mut node = &self.root;
+        loop {
+            match node {
+                Node::Split { dim, pos, children } => {
+                    match step(r, *dim, *pos, children, tmin, tmax) {
+                        Step::One(next) => node = next,
+                        Step::Both(near, far, t_split) => {
+                            stack.push((far, t_split, tmax));
+                            node = near;
+                            tmax = t_split;
+                        }
+                    }
+                    continue;
+                }
+                Node::Leaf(items) => {
+                    for &item in items {
+                        if let Some((t, hit)) = intersect(&self.items[item as usize], min, max) {
+                            max = t;
+                            result = Some(hit);
+                        }
+                    }
+                    // Hits in later cells are all further away than one inside this cell,
+                    // but items can span several cells, so a hit may lie beyond this one
+                    if result.is_some() && max <= tmax {
+                        return result;
+                    }
+                }
+            }
+            loop {
+                let (next, next_min, next_max) = match stack.pop() {
+                    Some(entry) => entry,
+                    None => return result,
+                };
+                if next_min <= max {
+                    node = next;
+                    tmin = next_min;
+                    tmax = next_max;
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Whether `hits` holds for any of the items in the leaves `r` passes through between
+    /// `min` and `max`, stopping at the first one it holds for.
+    pub fn any<F: FnMut(&T, f32, f32) -> bool>(
+        &self,
+        r: &Ray,
+        min: f32,
+        max: f32,
+        hits: &mut F,
+    ) -> bool {
+        let (mut tmin, mut tmax) = match self.bounds.clip(r, min, max) {
+            Some(range) => range,
+            None => return false,
         };
-        markers.push(start);
-        markers.push(end);
-    }
-    markers.par_sort_by(|a, b| a.partial_cmp(&b).expect("Tried sorting NaNs"));
-    markers
-}
-
-fn optimal_split<'a, I, G>(geoms: I, bounds: &AABB, dim: usize) -> Option<Split>
-where
-    I: Iterator<Item = &'a G>,
-    G: Bounds + 'a,
-{
-    const TRAVERSAL_COST: f32 = 1.0;
-    let markers = sorted_markers(geoms, dim);
-    let count = markers.len() / 2;
-    let (mut left, mut right): (usize, usize) = (0, count);
-    dedup_splits(
-        markers
-            .into_iter()
-            .map(|marker| {
-                let pos = marker.pos;
-                match marker.state {
-                    State::Start => left += 1,
-                    State::End => right -= 1,
+        let mut stack = Vec::new();
+        let mut node = &self.root;
+        loop {
+            match node {
+                Node::Split { dim, pos, children } => {
+                    match step(r, *dim, *pos, children, tmin, tmax) {
+                        Step::One(next) => node = next,
+                        Step::Both(near, far, t_split) => {
+                            stack.push((far, t_split, tmax));
+                            node = near;
+                            tmax = t_split;
+                        }
+                    }
+                    continue;
                 }
-                assert!(left <= count, right <= count);
-                let (l, r) = bounds.split_dimension(pos, dim);
-                let cost = TRAVERSAL_COST + cost(&l, left) + cost(&r, right);
-                Split { pos, dim, cost }
-            })
-            .peekable(),
-    )
-    .min_by(|a, b| a.cost.partial_cmp(&b.cost).expect("Tried comparing NaNs"))
-}
-
-fn dedup_splits<I: Iterator<Item = Split>>(
-    it: std::iter::Peekable<I>,
-) -> impl Iterator<Item = Split> {
-    struct Dedup<I: Iterator<Item = Split>> {
-        it: std::iter::Peekable<I>,
-    }
-    impl<I: Iterator<Item = Split>> Iterator for Dedup<I> {
-        type Item = Split;
-        fn next(&mut self) -> Option<Self::Item> {
-            let mut ret = self.it.next()?;
-            while let Some(next) = self.it.peek() {
-                if next.pos != ret.pos {
-                    return Some(ret);
+                Node::Leaf(items) => {
+                    if items
+                        .iter()
+                        .any(|&item| hits(&self.items[item as usize], min, max))
+                    {
+                        return true;
+                    }
+                }
+            }
+            match stack.pop() {
+                Some((next, next_min, next_max)) => {
+                    node = next;
+                    tmin = next_min;
+                    tmax = next_max;
                 }
-                ret = self.it.next()?;
+                None => return false,
             }
-            Some(ret)
         }
     }
-    Dedup { it }
 }
 
-fn partition_dimension<I, G>(geoms: I, split: f32, dimension: usize) -> (Vec<G>, Vec<G>)
-where
-    I: IntoIterator<Item = G>,
-    G: Bounds + Clone,
-{
-    let mut l_accum = Vec::new();
-    let mut r_accum = Vec::new();
-    for g in geoms.into_iter() {
-        let bounds = g.bounds();
-        if bounds.min[dimension] <= split {
-            l_accum.push(g.clone());
+impl<T: Geometry> Geometry for KdTree<T> {
+    fn intersection(&self, r: &Ray, min: f32, max: f32) -> Option<RayHit> {
+        self.traverse(r, min, max, &mut |tri, min, max| {
+            tri.intersection(r, min, max).map(|hit| (hit.t, hit))
+        })
+    }
+
+    fn occluded(&self, r: &Ray, min: f32, max: f32) -> bool {
+        self.any(r, min, max, &mut |tri, min, max| tri.occluded(r, min, max))
+    }
+}
+
+impl Node {
+    fn map_leaves<U, F: FnMut(&[u32]) -> Vec<U>>(&self, items: &mut Vec<U>, f: &mut F) -> Node {
+        match self {
+            Node::Leaf(indices) => {
+                let first = items.len() as u32;
+                items.extend(f(indices));
+                Node::Leaf((first..items.len() as u32).collect())
+            }
+            Node::Split { dim, pos, children } => Node::Split {
+                dim: *dim,
+                pos: *pos,
+                children: Box::new([
+                    children[0].map_leaves(items, f),
+                    children[1].map_leaves(items, f),
+                ]),
+            },
         }
-        if bounds.max[dimension] > split {
-            r_accum.push(g);
+    }
+
+    /// Whether every leaf below refers to one of the first `count` items.
+    fn in_bounds(&self, count: usize) -> bool {
+        match self {
+            Node::Leaf(items) => items.iter().all(|&i| (i as usize) < count),
+            Node::Split { children, .. } => children.iter().all(|c| c.in_bounds(count)),
         }
     }
-    (l_accum, r_accum)
 }
 
-impl<T: Geometry> Geometry for KdTree<T> {
-    fn intersection(&self, r: &Ray, min: f32, max: f32) -> Option<RayHit> {
+impl<T: Binary> Binary for KdTree<T> {
+    fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.items.write(w)?;
+        self.bounds.write(w)?;
+        self.root.write(w)
+    }
+
+    /// Fails if any leaf refers to an item that isn't there, so that a corrupt file
+    /// can't make traversal index out of bounds.
+    fn read<R: Read>(r: &mut R) -> io::Result<Self> {
+        let tree = KdTree {
+            items: Vec::read(r)?,
+            bounds: AABB::read(r)?,
+            root: Node::read(r)?,
+        };
+        if !tree.root.in_bounds(tree.items.len()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Kd-tree leaf refers to a missing item",
+            ));
+        }
+        Ok(tree)
+    }
+}
+
+/// Nodes are written depth first, each leaf as its item indices and each split as its
+/// axis, or 3 for leaves, followed by its position and children.
+impl Binary for Node {
+    fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
         match self {
-            KdTree::Leaf { bounds, geoms } if bounds.intersects(r) => {
-                let mut max = max;
-                let mut result = None;
-                for tri in geoms {
-                    let hit_result = tri.intersection(r, min, max);
-                    if let Some(hit) = &hit_result {
-                        max = hit.t;
-                        result = hit_result;
-                    }
-                }
-                result
+            Node::Leaf(items) => {
+                3u32.write(w)?;
+                items.write(w)
             }
-            KdTree::Node {
-                bounds,
-                left,
-                right,
-            } if bounds.intersects(r) => {
-                let mut max = max;
-                let left = left.intersection(r, min, max);
-                if let Some(hit) = &left {
-                    max = hit.t;
-                }
-                let right = right.intersection(r, min, max);
-                right.or(left)
+            Node::Split { dim, pos, children } => {
+                (*dim as u32).write(w)?;
+                pos.write(w)?;
+                children[0].write(w)?;
+                children[1].write(w)
             }
-            _ => None,
+        }
+    }
+
+    fn read<R: Read>(r: &mut R) -> io::Result<Self> {
+        match u32::read(r)? {
+            3 => Ok(Node::Leaf(Vec::read(r)?)),
+            dim if dim < 3 => Ok(Node::Split {
+                dim: dim as usize,
+                pos: f32::read(r)?,
+                children: Box::new([Node::read(r)?, Node::read(r)?]),
+            }),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Invalid kd-tree node",
+            )),
         }
     }
 }
 
 impl<T> Bounds for KdTree<T> {
     fn bounds(&self) -> AABB {
-        match self {
-            KdTree::Leaf { bounds, .. } | KdTree::Node { bounds, .. } => bounds.clone(),
-        }
+        self.bounds.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vec::glm;
+
+    fn boxes() -> Vec<AABB> {
+        (0..3)
+            .map(|i| AABB {
+                min: glm::vec3(i as f32, 0.0, 0.0),
+                max: glm::vec3(i as f32 + 0.5, 1.0, 1.0),
+            })
+            .collect()
+    }
+
+    fn round_trip(tree: &KdTree<AABB>) -> io::Result<KdTree<AABB>> {
+        let mut bytes = Vec::new();
+        tree.write(&mut bytes)?;
+        KdTree::read(&mut bytes.as_slice())
+    }
+
+    #[test]
+    fn reads_back_what_it_writes() {
+        let tree = KdTree::<AABB>::new(boxes(), &KdTreeConfig::default());
+        let read = round_trip(&tree).unwrap();
+        assert_eq!(read.items().len(), 3);
+        let mut bytes = (Vec::new(), Vec::new());
+        tree.write(&mut bytes.0).unwrap();
+        read.write(&mut bytes.1).unwrap();
+        assert_eq!(bytes.0, bytes.1);
+    }
+
+    #[test]
+    fn rejects_leaves_with_missing_items() {
+        let items = boxes();
+        let tree = KdTree {
+            bounds: AABB::from(items.iter().flat_map(|b| vec![&b.min, &b.max])),
+            items,
+            root: Node::Split {
+                dim: 0,
+                pos: 1.0,
+                children: Box::new([Node::Leaf(vec![0]), Node::Leaf(vec![1, 2, 3])]),
+            },
+        };
+        assert!(round_trip(&tree).is_err());
+    }
+
+    #[test]
+    fn batches_make_splitting_leaves_less_worth_it() {
+        let items = || {
+            (0..4)
+                .map(|i| AABB {
+                    min: glm::vec3(i as f32 * 2.0, 0.0, 0.0),
+                    max: glm::vec3(i as f32 * 2.0 + 1.0, 1.0, 1.0),
+                })
+                .collect::<Vec<_>>()
+        };
+        let config = KdTreeConfig::default();
+        let single = KdTree::new(items(), &config);
+        let batched = KdTree::with_batch(items(), &config, 4);
+        assert!(matches!(single.root, Node::Split { .. }));
+        assert!(matches!(batched.root, Node::Leaf(_)));
+    }
+
+    #[test]
+    fn maps_the_items_of_every_leaf() {
+        let tree = KdTree::new(boxes(), &KdTreeConfig::default());
+        let mut leaves = Vec::new();
+        let mapped = tree.map_leaves(|indices| {
+            leaves.push(indices.to_vec());
+            indices.to_vec()
+        });
+        let mut items = mapped.items().to_vec();
+        assert_eq!(items, leaves.concat());
+        items.sort_unstable();
+        items.dedup();
+        assert_eq!(items, vec![0, 1, 2]);
+        assert!(mapped.root.in_bounds(mapped.items().len()));
     }
 }
@@ -1,8 +1,11 @@
-use std::path::Path;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
 
 use nalgebra_glm as glm;
 use serde::{Deserialize, Deserializer};
 
+use super::cache::{self, Binary};
 use super::*;
 use crate::obj;
 use crate::ray::Ray;
@@ -20,8 +23,74 @@ pub struct Triangle {
     verts: [Vertex; 3],
 }
 
+/// Normals and texture coordinates at the corners of a triangle. Meshes keep these apart
+/// from the positions, which only their packets hold.
+#[derive(Clone)]
+pub struct Shading {
+    normals: [Vec3; 3],
+    uvs: [Vec2; 3],
+}
+
 pub struct Mesh {
-    tree: KdTree<Triangle>,
+    /// OBJ file the triangles are loaded from
+    path: PathBuf,
+    /// Kind of acceleration structure to use instead of the scene's default
+    accel: Option<AccelType>,
+    /// Triangles and the structures over them, loaded once the scene is parsed
+    data: Option<MeshData>,
+}
+
+struct MeshData {
+    /// Acceleration structure built over single triangles, with the triangles of each leaf
+    /// grouped into packets. Packets are the only place the triangle positions are kept,
+    /// so kd-trees hold a copy of every triangle for each leaf it overlaps.
+    tree: Accel<TrianglePacket>,
+    /// Shading of each triangle
+    shading: Vec<Shading>,
+    /// Position among the tree's items of the packet holding each triangle, and its lane
+    locations: Vec<(u32, u32)>,
+    /// Cumulative areas of the triangles, used to sample points uniformly over the surface
+    area_cdf: Vec<f32>,
+}
+
+impl MeshData {
+    /// Builds a tree of kind `accel` over `triangles`, then packs the triangles of each
+    /// of its leaves together.
+    fn build(triangles: Vec<Triangle>, accel: AccelType, kd_tree: &KdTreeConfig) -> Self {
+        let triangles = accel.build_batched(triangles, kd_tree, LANES);
+        let items = triangles.items();
+        let shading = items.iter().map(Triangle::shading).collect();
+        let tree = triangles
+            .map_leaves(|ids| TrianglePacket::group(ids, |id| items[id as usize].positions()));
+        MeshData::new(tree, shading)
+    }
+
+    /// `tree` must hold packets for exactly the triangles in `shading`.
+    fn new(tree: Accel<TrianglePacket>, shading: Vec<Shading>) -> Self {
+        let locations = TrianglePacket::locate(tree.items(), shading.len())
+            .expect("Packets don't hold the mesh's triangles");
+        let area_cdf = locations
+            .iter()
+            .map(|&(packet, lane)| tree.items()[packet as usize].positions(lane as usize))
+            .scan(0.0, |acc, positions| {
+                *acc += triangle_area(positions);
+                Some(*acc)
+            })
+            .collect();
+        MeshData {
+            tree,
+            shading,
+            locations,
+            area_cdf,
+        }
+    }
+
+    /// Positions and shading of the triangle at `index`.
+    fn triangle(&self, index: usize) -> ((Vec3, Vec3, Vec3), &Shading) {
+        let (packet, lane) = self.locations[index];
+        let packet = &self.tree.items()[packet as usize];
+        (packet.positions(lane as usize), &self.shading[index])
+    }
 }
 
 impl Triangle {
@@ -35,10 +104,36 @@ impl Triangle {
         (self.verts[0].pos, self.verts[1].pos, self.verts[2].pos)
     }
 
-    fn interpolate(&self, p: &Vec3) -> Vertex {
-        let triangle_area = |e0: Vec3, e1: Vec3| glm::length(&e0.cross(&e1));
+    pub(super) fn shading(&self) -> Shading {
         let [v0, v1, v2] = &self.verts;
-        let (p0, p1, p2) = self.positions();
+        Shading {
+            normals: [v0.normal, v1.normal, v2.normal],
+            uvs: [v0.uv, v1.uv, v2.uv],
+        }
+    }
+}
+
+fn triangle_area((p0, p1, p2): (Vec3, Vec3, Vec3)) -> f32 {
+    0.5 * glm::length(&(p1 - p0).cross(&(p2 - p0)))
+}
+
+/// Unit normal of the plane through `positions`, on the side the triangle faces.
+fn geometric_normal((p0, p1, p2): (Vec3, Vec3, Vec3)) -> Vec3 {
+    glm::normalize(&(p1 - p0).cross(&(p2 - p0)))
+}
+
+/// Uniformly distributed point on the triangle with corners at `positions`.
+fn sample_triangle((p0, p1, p2): (Vec3, Vec3, Vec3), u: Vec2) -> Vec3 {
+    let su = f32::sqrt(u.x);
+    let b1 = 1.0 - su;
+    let b2 = u.y * su;
+    p0 * b1 + p1 * b2 + p2 * (1.0 - b1 - b2)
+}
+
+impl Shading {
+    /// Shading at point `p` of the triangle with corners at `positions`.
+    fn interpolate(&self, (p0, p1, p2): (Vec3, Vec3, Vec3), p: &Vec3) -> Vertex {
+        let triangle_area = |e0: Vec3, e1: Vec3| glm::length(&e0.cross(&e1));
         let f0 = p0 - p;
         let f1 = p1 - p;
         let f2 = p2 - p;
@@ -46,18 +141,50 @@ impl Triangle {
         let a0 = triangle_area(f1, f2) / a;
         let a1 = triangle_area(f2, f0) / a;
         let a2 = triangle_area(f0, f1) / a;
-        let uv = v0.uv * a0 + v1.uv * a1 + v2.uv * a2;
-        let normal = v0.normal * a0 + v1.normal * a1 + v2.normal * a2;
+        let [uv0, uv1, uv2] = self.uvs;
+        let [n0, n1, n2] = self.normals;
+        let uv = uv0 * a0 + uv1 * a1 + uv2 * a2;
+        let normal = n0 * a0 + n1 * a1 + n2 * a2;
         Vertex {
             pos: *p,
             uv,
             normal,
         }
     }
+
+    /// Hit at distance `t` along `r` on the triangle with corners at `positions`,
+    /// which must be where `r` meets it.
+    fn hit_at(&self, positions: (Vec3, Vec3, Vec3), r: &Ray, t: f32) -> RayHit {
+        let point = r.point_at(t);
+        let Vertex { uv, normal, .. } = self.interpolate(positions, &point);
+        RayHit {
+            t,
+            point,
+            normal,
+            geometric_normal: geometric_normal(positions),
+            uv,
+            time: r.time,
+        }
+    }
 }
 
-impl Geometry for Triangle {
-    fn intersection(&self, r: &Ray, min: f32, max: f32) -> Option<RayHit> {
+impl Binary for Shading {
+    fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.normals.iter().try_for_each(|n| n.write(w))?;
+        self.uvs.iter().try_for_each(|uv| uv.write(w))
+    }
+
+    fn read<R: Read>(r: &mut R) -> io::Result<Self> {
+        Ok(Shading {
+            normals: [Vec3::read(r)?, Vec3::read(r)?, Vec3::read(r)?],
+            uvs: [Vec2::read(r)?, Vec2::read(r)?, Vec2::read(r)?],
+        })
+    }
+}
+
+impl Triangle {
+    /// Distance along `r` to the front face of the triangle, if it lies between `min` and `max`.
+    fn distance(&self, r: &Ray, min: f32, max: f32) -> Option<f32> {
         let (v0, v1, v2) = self.positions();
         let e1 = v1 - v0;
         let e2 = v2 - v0;
@@ -73,20 +200,21 @@ impl Geometry for Triangle {
             let uv = glm::vec2(tvec.dot(&pvec), r.direction.dot(&qvec)) * idet;
             if uv.x >= 0.0 && uv.x <= 1.0 && uv.y >= 0.0 && uv.x + uv.y <= 1.0 && t > min && t < max
             {
-                let point = r.point_at(t);
-                let Vertex { uv, normal, .. } = self.interpolate(&point);
-                Some(RayHit {
-                    t,
-                    point,
-                    normal,
-                    uv,
-                })
-            } else {
-                None
+                return Some(t);
             }
-        } else {
-            None
         }
+        None
+    }
+}
+
+impl Geometry for Triangle {
+    fn intersection(&self, r: &Ray, min: f32, max: f32) -> Option<RayHit> {
+        let t = self.distance(r, min, max)?;
+        Some(self.shading().hit_at(self.positions(), r, t))
+    }
+
+    fn occluded(&self, r: &Ray, min: f32, max: f32) -> bool {
+        self.distance(r, min, max).is_some()
     }
 }
 
@@ -94,32 +222,297 @@ impl Bounds for Triangle {
     fn bounds(&self) -> AABB {
         AABB::from(self.verts.iter().map(|v| &v.pos))
     }
+
+    fn clipped_bounds(&self, cell: &AABB) -> AABB {
+        let (p0, p1, p2) = self.positions();
+        let mut polygon = vec![p0, p1, p2];
+        for dim in 0..3 {
+            polygon = clip_polygon(&polygon, dim, cell.min[dim], true);
+            polygon = clip_polygon(&polygon, dim, cell.max[dim], false);
+        }
+        if polygon.is_empty() {
+            // Only touches the cell, or rounding made it miss it
+            return self.bounds().intersection(cell);
+        }
+        AABB::from(polygon.iter()).intersection(cell)
+    }
+}
+
+/// Part of the convex `polygon` above the plane at `pos` along `dim` if `above`,
+/// or below it otherwise.
+fn clip_polygon(polygon: &[Vec3], dim: usize, pos: f32, above: bool) -> Vec<Vec3> {
+    let inside = |p: &Vec3| if above { p[dim] >= pos } else { p[dim] <= pos };
+    let mut clipped = Vec::with_capacity(polygon.len() + 1);
+    for (i, a) in polygon.iter().enumerate() {
+        let b = &polygon[(i + 1) % polygon.len()];
+        if inside(a) {
+            clipped.push(*a);
+        }
+        if inside(a) != inside(b) {
+            let t = (pos - a[dim]) / (b[dim] - a[dim]);
+            clipped.push(a + (b - a) * t);
+        }
+    }
+    clipped
 }
 
 impl Mesh {
-    pub fn from_file<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
-        let tris = obj::load(path)?;
-        let tree = KdTree::new(tris);
-        Ok(Mesh { tree })
+    pub fn new<P: Into<PathBuf>>(path: P, accel: Option<AccelType>) -> Self {
+        Mesh {
+            path: path.into(),
+            accel,
+            data: None,
+        }
+    }
+
+    /// Loads the triangles and builds the mesh's acceleration structure, of kind `default`
+    /// unless the mesh asks for another one. Both are read from the mesh's cache file
+    /// instead if it was written for the same file and build settings.
+    /// Does nothing if the mesh was already built.
+    pub fn build(&mut self, default: AccelType, kd_tree: &KdTreeConfig) -> io::Result<()> {
+        if self.data.is_some() {
+            return Ok(());
+        }
+        let accel = self.accel.unwrap_or(default);
+        let source = fs::read(&self.path)?;
+        let key = cache::Key::new(&source, accel, kd_tree);
+        let cache_path = cache::path(&self.path, &key);
+        let data = match cache::load(&cache_path, &key) {
+            Ok((tree, shading)) => MeshData::new(tree, shading),
+            Err(_) => {
+                let text = std::str::from_utf8(&source)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                let data = MeshData::build(obj::parse(text), accel, kd_tree);
+                let saved = cache::save(&cache_path, &key, &data.tree, &data.shading)
+                    .and_then(|_| cache::remove_stale(&self.path, &key));
+                if let Err(e) = saved {
+                    eprintln!(
+                        "Could not write mesh cache to {}: {}",
+                        cache_path.display(),
+                        e
+                    );
+                }
+                data
+            }
+        };
+        self.data = Some(data);
+        Ok(())
+    }
+
+    /// Builds the mesh's acceleration structure over `triangles` instead of the ones in
+    /// its file, without touching its cache. Does nothing if the mesh was already built.
+    pub fn build_from(
+        &mut self,
+        triangles: Vec<Triangle>,
+        default: AccelType,
+        kd_tree: &KdTreeConfig,
+    ) {
+        if self.data.is_none() {
+            let accel = self.accel.unwrap_or(default);
+            self.data = Some(MeshData::build(triangles, accel, kd_tree));
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn data(&self) -> &MeshData {
+        self.data
+            .as_ref()
+            .expect("Mesh was used before loading its triangles")
+    }
+
+    pub fn area(&self) -> f32 {
+        self.data().area_cdf.last().cloned().unwrap_or(0.0)
+    }
+}
+
+impl Emitter for Mesh {
+    fn sample_towards(&self, origin: &Vec3, _time: f32, u: Vec2) -> Option<SurfaceSample> {
+        let data = self.data();
+        let area_cdf = &data.area_cdf;
+        let area = self.area();
+        if area <= 0.0 {
+            return None;
+        }
+        // Pick a triangle proportionally to its area, then reuse u.x within it
+        let target = u.x * area;
+        let idx = usize::min(
+            area_cdf.partition_point(|&a| a <= target),
+            area_cdf.len() - 1,
+        );
+        let start = if idx == 0 { 0.0 } else { area_cdf[idx - 1] };
+        let tri_area = area_cdf[idx] - start;
+        let remapped = f32::min((target - start) / tri_area, 1.0);
+        let (positions, shading) = data.triangle(idx);
+        let point = sample_triangle(positions, glm::vec2(remapped, u.y));
+        let Vertex { pos, uv, .. } = shading.interpolate(positions, &point);
+        // Densities are measured on the surface itself, whatever the shading normals
+        let normal = geometric_normal(positions);
+        let pdf = area_to_solid_angle(1.0 / area, origin, &pos, &normal);
+        Some(SurfaceSample {
+            point: pos,
+            normal,
+            uv,
+            pdf,
+        })
+    }
+
+    fn pdf_towards(&self, origin: &Vec3, hit: &RayHit) -> f32 {
+        area_to_solid_angle(1.0 / self.area(), origin, &hit.point, &hit.geometric_normal)
+    }
+}
+
+impl Geometry for MeshData {
+    fn intersection(&self, r: &Ray, min: f32, max: f32) -> Option<RayHit> {
+        let lanes = RayLanes::new(r);
+        let (t, packet, lane) = self.tree.traverse(r, min, max, &mut |packet, min, max| {
+            let (t, lane) = packet.intersect(&lanes, min, max)?;
+            Some((t, (t, packet, lane)))
+        })?;
+        let shading = &self.shading[packet.id(lane)];
+        Some(shading.hit_at(packet.positions(lane), r, t))
+    }
+
+    fn occluded(&self, r: &Ray, min: f32, max: f32) -> bool {
+        let lanes = RayLanes::new(r);
+        self.tree.any(r, min, max, &mut |packet, min, max| {
+            packet.occluded(&lanes, min, max)
+        })
     }
 }
 
 impl Geometry for Mesh {
     fn intersection(&self, r: &Ray, min: f32, max: f32) -> Option<RayHit> {
-        self.tree.intersection(r, min, max)
+        self.data().intersection(r, min, max)
+    }
+
+    fn occluded(&self, r: &Ray, min: f32, max: f32) -> bool {
+        self.data().occluded(r, min, max)
     }
 }
 
 impl Bounds for Mesh {
     fn bounds(&self) -> AABB {
-        self.tree.bounds()
+        self.data().tree.bounds()
     }
 }
 
 impl<'de> Deserialize<'de> for Mesh {
     fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
-        let s = String::deserialize(deserializer)?;
-        let mesh = Mesh::from_file(&s).map_err(serde::de::Error::custom)?;
-        Ok(mesh)
+        /// Either just the path to an OBJ file, or a table with the path and options
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum MeshDesc {
+            Path(String),
+            Options {
+                path: String,
+                accel: Option<AccelType>,
+            },
+        }
+        let (path, accel) = match MeshDesc::deserialize(deserializer)? {
+            MeshDesc::Path(path) => (path, None),
+            MeshDesc::Options { path, accel } => (path, accel),
+        };
+        Ok(Mesh::new(path, accel))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::accel::tests::{assert_matches_brute_force, random_rays, random_triangles};
+    use super::*;
+
+    fn mesh(triangles: Vec<Triangle>) -> Mesh {
+        Mesh {
+            path: PathBuf::new(),
+            accel: None,
+            data: Some(MeshData::build(
+                triangles,
+                AccelType::Bvh,
+                &KdTreeConfig::default(),
+            )),
+        }
+    }
+
+    /// Solid angle of the triangle with corners `a`, `b` and `c` as seen from the origin,
+    /// by Van Oosterom and Strackee's formula.
+    fn solid_angle(a: &Vec3, b: &Vec3, c: &Vec3) -> f32 {
+        let (la, lb, lc) = (glm::length(a), glm::length(b), glm::length(c));
+        let numerator = a.dot(&b.cross(c)).abs();
+        let denominator = la * lb * lc + a.dot(b) * lc + a.dot(c) * lb + b.dot(c) * la;
+        2.0 * f32::atan2(numerator, denominator)
+    }
+
+    #[test]
+    fn light_densities_integrate_to_the_solid_angle() {
+        let positions = [
+            glm::vec3(-1.0, 0.0, -1.0),
+            glm::vec3(0.0, 0.0, 1.0),
+            glm::vec3(1.0, 0.0, -1.0),
+        ];
+        let origin = glm::vec3(0.3, 2.0, 0.5);
+        let expected = solid_angle(
+            &(positions[0] - origin),
+            &(positions[1] - origin),
+            &(positions[2] - origin),
+        );
+        let flat = [glm::vec3(0.0, 1.0, 0.0); 3];
+        // Vertex normals that diverge, so the shading normal is far from the surface's
+        let smooth = [
+            glm::vec3(-0.7, 0.7, 0.0),
+            glm::vec3(0.0, 0.7, 0.7),
+            glm::vec3(0.7, 0.7, 0.0),
+        ];
+        for normals in &[flat, smooth] {
+            let vertex = |i: usize| Vertex {
+                pos: positions[i],
+                normal: normals[i],
+                uv: glm::zero(),
+            };
+            let mesh = mesh(vec![Triangle::new(vertex(0), vertex(1), vertex(2))]);
+            // The expected value of 1 / pdf over the samples is the solid angle they cover
+            let n = 256;
+            let mut sum = 0.0;
+            for i in 0..n * n {
+                let u = glm::vec2(
+                    ((i % n) as f32 + 0.5) / n as f32,
+                    ((i / n) as f32 + 0.5) / n as f32,
+                );
+                let sample = mesh.sample_towards(&origin, 0.0, u).unwrap();
+                sum += 1.0 / sample.pdf;
+                if i % 97 == 0 {
+                    let direction = sample.point - origin;
+                    let hit = mesh
+                        .intersection(&Ray::new(origin, direction), 0.0, 2.0)
+                        .unwrap();
+                    let pdf = mesh.pdf_towards(&origin, &hit);
+                    assert!((pdf - sample.pdf).abs() <= 1e-3 * sample.pdf);
+                }
+            }
+            let estimate = sum / (n * n) as f32;
+            assert!((estimate - expected).abs() <= 1e-3 * expected);
+        }
+    }
+
+    #[test]
+    fn packets_find_the_same_hits_as_single_triangles() {
+        let items = random_triangles(201);
+        let rays = random_rays(500);
+        let config = KdTreeConfig::default();
+        for &accel in &[AccelType::KdTree, AccelType::Bvh] {
+            let data = MeshData::build(items.clone(), accel, &config);
+            assert_matches_brute_force(&data, &items, &rays);
+            for ray in &rays {
+                let expected = items
+                    .iter()
+                    .filter_map(|tri| tri.intersection(ray, 0.0, f32::MAX))
+                    .min_by(|a, b| a.t.total_cmp(&b.t));
+                let hit = data.intersection(ray, 0.0, f32::MAX);
+                let fields = |h: RayHit| (h.t, h.point, h.normal, h.uv);
+                assert_eq!(hit.map(fields), expected.map(fields));
+            }
+        }
     }
 }
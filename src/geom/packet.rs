@@ -0,0 +1,287 @@
+use std::array;
+use std::io::{self, Read, Write};
+
+use wide::f32x4;
+
+use super::cache::Binary;
+use crate::ray::Ray;
+use crate::Vec3;
+
+/// Number of triangles tested against a ray at once
+pub const LANES: usize = 4;
+
+/// A value for each lane, with arithmetic applied to all of them at once using whichever
+/// SIMD instructions the target has, or plain arrays where it has none.
+type Lanes = f32x4;
+
+/// One bit per lane, with lane `i` in bit `i`
+type Mask = u32;
+
+/// A vector for each lane, stored as one array per coordinate.
+#[derive(Clone, Copy)]
+struct Vec3Lanes {
+    x: Lanes,
+    y: Lanes,
+    z: Lanes,
+}
+
+/// The vector operations use the same order of operations as nalgebra, so every lane
+/// gets exactly the same results as testing its triangle on its own.
+impl Vec3Lanes {
+    fn new(vecs: &[Vec3; LANES]) -> Self {
+        Vec3Lanes {
+            x: Lanes::new(array::from_fn(|i| vecs[i].x)),
+            y: Lanes::new(array::from_fn(|i| vecs[i].y)),
+            z: Lanes::new(array::from_fn(|i| vecs[i].z)),
+        }
+    }
+
+    fn splat(v: &Vec3) -> Self {
+        Vec3Lanes {
+            x: Lanes::splat(v.x),
+            y: Lanes::splat(v.y),
+            z: Lanes::splat(v.z),
+        }
+    }
+
+    fn sub(&self, other: &Self) -> Self {
+        Vec3Lanes {
+            x: self.x - other.x,
+            y: self.y - other.y,
+            z: self.z - other.z,
+        }
+    }
+
+    fn cross(&self, other: &Self) -> Self {
+        Vec3Lanes {
+            x: self.y * other.z - self.z * other.y,
+            y: self.z * other.x - self.x * other.z,
+            z: self.x * other.y - self.y * other.x,
+        }
+    }
+
+    fn dot(&self, other: &Self) -> Lanes {
+        self.x * other.x + self.y * other.y + self.z * other.z
+    }
+
+    fn lane(&self, lane: usize) -> Vec3 {
+        Vec3::new(
+            self.x.to_array()[lane],
+            self.y.to_array()[lane],
+            self.z.to_array()[lane],
+        )
+    }
+}
+
+/// A ray's origin and direction, repeated in every lane.
+pub struct RayLanes {
+    origin: Vec3Lanes,
+    direction: Vec3Lanes,
+}
+
+impl RayLanes {
+    pub fn new(r: &Ray) -> Self {
+        RayLanes {
+            origin: Vec3Lanes::splat(&r.origin),
+            direction: Vec3Lanes::splat(&r.direction),
+        }
+    }
+}
+
+/// Up to `LANES` triangles of a tree leaf, laid out to be intersected with a ray at once.
+/// Meshes keep the positions of their triangles nowhere else.
+pub struct TrianglePacket {
+    v0: Vec3Lanes,
+    v1: Vec3Lanes,
+    v2: Vec3Lanes,
+    /// Index of the triangle in every lane
+    ids: [u32; LANES],
+    /// Number of lanes holding a triangle. The remaining lanes repeat the last one,
+    /// which can only ever find the same hit again.
+    count: u32,
+}
+
+impl TrianglePacket {
+    /// Splits the triangles at `ids` into packets, all of them full but the last.
+    /// `positions` gives the corners of the triangle with an index.
+    pub fn group<F>(ids: &[u32], positions: F) -> Vec<Self>
+    where
+        F: Fn(u32) -> (Vec3, Vec3, Vec3),
+    {
+        ids.chunks(LANES)
+            .map(|chunk| {
+                let ids = array::from_fn(|lane| chunk[usize::min(lane, chunk.len() - 1)]);
+                let corners = ids.map(&positions);
+                TrianglePacket {
+                    v0: Vec3Lanes::new(&corners.map(|(v0, _, _)| v0)),
+                    v1: Vec3Lanes::new(&corners.map(|(_, v1, _)| v1)),
+                    v2: Vec3Lanes::new(&corners.map(|(_, _, v2)| v2)),
+                    ids,
+                    count: chunk.len() as u32,
+                }
+            })
+            .collect()
+    }
+
+    /// Packet and lane holding each of `count` triangles, or `None` unless every packet
+    /// holds between 1 and `LANES` of them and each of them is in a packet.
+    pub fn locate(packets: &[Self], count: usize) -> Option<Vec<(u32, u32)>> {
+        let mut locations = vec![None; count];
+        for (i, packet) in packets.iter().enumerate() {
+            if packet.count == 0 || packet.count() > LANES {
+                return None;
+            }
+            for lane in 0..packet.count() {
+                let location = locations.get_mut(packet.id(lane))?;
+                location.get_or_insert((i as u32, lane as u32));
+            }
+        }
+        locations.into_iter().collect()
+    }
+
+    /// Number of triangles in the packet.
+    pub fn count(&self) -> usize {
+        self.count as usize
+    }
+
+    /// Index of the triangle in `lane`.
+    pub fn id(&self, lane: usize) -> usize {
+        self.ids[lane] as usize
+    }
+
+    /// Vertex positions of the triangle in `lane`.
+    pub fn positions(&self, lane: usize) -> (Vec3, Vec3, Vec3) {
+        (self.v0.lane(lane), self.v1.lane(lane), self.v2.lane(lane))
+    }
+
+    /// Lanes whose triangle `r` hits from the front between `min` and `max`, along with the
+    /// distance to each of them. Same test as `Triangle::intersection`.
+    fn hits(&self, r: &RayLanes, min: f32, max: f32) -> (Mask, Lanes) {
+        let e1 = self.v1.sub(&self.v0);
+        let e2 = self.v2.sub(&self.v0);
+        let pvec = r.direction.cross(&e2);
+        let det = e1.dot(&pvec);
+        let idet = Lanes::splat(1.0) / det;
+        let tvec = r.origin.sub(&self.v0);
+        let qvec = tvec.cross(&e1);
+        let t = e2.dot(&qvec) * idet;
+        let u = tvec.dot(&pvec) * idet;
+        let v = r.direction.dot(&qvec) * idet;
+        let (zero, one) = (Lanes::splat(0.0), Lanes::splat(1.0));
+        let hits = det.is_sign_positive()
+            & zero.simd_le(u)
+            & u.simd_le(one)
+            & zero.simd_le(v)
+            & (u + v).simd_le(one)
+            & Lanes::splat(min).simd_lt(t)
+            & t.simd_lt(Lanes::splat(max));
+        (hits.to_bitmask(), t)
+    }
+
+    /// Distance to the closest triangle hit by `r` between `min` and `max`,
+    /// along with its lane. Ties go to the earlier lane.
+    pub fn intersect(&self, r: &RayLanes, min: f32, max: f32) -> Option<(f32, usize)> {
+        let (hits, t) = self.hits(r, min, max);
+        if hits == 0 {
+            return None;
+        }
+        let t = t.to_array();
+        (0..LANES)
+            .filter(|lane| hits & 1 << lane != 0)
+            .fold(None, |closest, lane| match closest {
+                Some((t_closest, _)) if t_closest <= t[lane] => closest,
+                _ => Some((t[lane], lane)),
+            })
+    }
+
+    pub fn occluded(&self, r: &RayLanes, min: f32, max: f32) -> bool {
+        self.hits(r, min, max).0 != 0
+    }
+}
+
+impl Binary for Lanes {
+    fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.to_array().iter().try_for_each(|x| x.write(w))
+    }
+
+    fn read<R: Read>(r: &mut R) -> io::Result<Self> {
+        let mut lanes = [0.0; LANES];
+        for x in lanes.iter_mut() {
+            *x = f32::read(r)?;
+        }
+        Ok(Lanes::new(lanes))
+    }
+}
+
+impl Binary for Vec3Lanes {
+    fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.x.write(w)?;
+        self.y.write(w)?;
+        self.z.write(w)
+    }
+
+    fn read<R: Read>(r: &mut R) -> io::Result<Self> {
+        Ok(Vec3Lanes {
+            x: Lanes::read(r)?,
+            y: Lanes::read(r)?,
+            z: Lanes::read(r)?,
+        })
+    }
+}
+
+impl Binary for TrianglePacket {
+    fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.v0.write(w)?;
+        self.v1.write(w)?;
+        self.v2.write(w)?;
+        self.ids.iter().try_for_each(|id| id.write(w))?;
+        self.count.write(w)
+    }
+
+    fn read<R: Read>(r: &mut R) -> io::Result<Self> {
+        Ok(TrianglePacket {
+            v0: Vec3Lanes::read(r)?,
+            v1: Vec3Lanes::read(r)?,
+            v2: Vec3Lanes::read(r)?,
+            ids: [u32::read(r)?, u32::read(r)?, u32::read(r)?, u32::read(r)?],
+            count: u32::read(r)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vec::glm;
+
+    fn positions(id: u32) -> (Vec3, Vec3, Vec3) {
+        let x = id as f32;
+        (
+            glm::vec3(x, 0.0, 0.0),
+            glm::vec3(x, 1.0, 0.0),
+            glm::vec3(x, 0.0, 1.0),
+        )
+    }
+
+    #[test]
+    fn groups_ids_into_full_packets_but_the_last() {
+        let packets = TrianglePacket::group(&[4, 1, 3, 0, 2], positions);
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0].count(), LANES);
+        assert_eq!(packets[1].count(), 1);
+        assert_eq!(packets[1].id(0), 2);
+        // Padding repeats the last triangle
+        assert_eq!(packets[1].id(LANES - 1), 2);
+        assert_eq!(packets[1].positions(LANES - 1), positions(2));
+        let locations = TrianglePacket::locate(&packets, 5).unwrap();
+        assert_eq!(locations, vec![(0, 3), (0, 1), (1, 0), (0, 2), (0, 0)]);
+    }
+
+    #[test]
+    fn locates_only_packets_holding_every_triangle() {
+        let packets = TrianglePacket::group(&[0, 1, 2], positions);
+        assert!(TrianglePacket::locate(&packets, 3).is_some());
+        assert!(TrianglePacket::locate(&packets, 4).is_none());
+        assert!(TrianglePacket::locate(&packets, 2).is_none());
+    }
+}
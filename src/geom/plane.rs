@@ -28,37 +28,76 @@ impl Plane {
         let proj2 = glm::dot(&v, &side2) / height;
         proj1 < width && proj1 > 0.0 && proj2 < height && proj2 > 0.0
     }
+
+    pub fn area(&self) -> f32 {
+        let side1 = self.points[1] - self.points[0];
+        let side2 = self.points[3] - self.points[0];
+        glm::length(&side1.cross(&side2))
+    }
 }
 
-impl Geometry for Plane {
-    fn intersection(&self, r: &Ray, min: f32, max: f32) -> Option<RayHit> {
+impl Emitter for Plane {
+    fn sample_towards(&self, origin: &Vec3, _time: f32, u: Vec2) -> Option<SurfaceSample> {
+        let side1 = self.points[1] - self.points[0];
+        let side2 = self.points[3] - self.points[0];
+        let point = self.points[0] + side1 * u.x + side2 * u.y;
+        let normal = self.normal();
+        let pdf = area_to_solid_angle(1.0 / self.area(), origin, &point, &normal);
+        Some(SurfaceSample {
+            point,
+            normal,
+            uv: u,
+            pdf,
+        })
+    }
+
+    fn pdf_towards(&self, origin: &Vec3, hit: &RayHit) -> f32 {
+        area_to_solid_angle(1.0 / self.area(), origin, &hit.point, &hit.geometric_normal)
+    }
+}
+
+impl Plane {
+    /// Distance along `r` to the plane, if it hits inside the quad between `min` and `max`.
+    fn distance(&self, r: &Ray, min: f32, max: f32) -> Option<f32> {
         let normal = self.normal();
         let denom = glm::dot(&r.direction, &normal);
-        if denom.abs() > 0.0001 {
-            let num = glm::dot(&(self.points[0] - r.origin), &normal);
-            let t = num / denom;
-            let point = r.point_at(t);
-            if self.contains(point) && t > min && t < max {
-                let x = self.points[1] - self.points[0];
-                let y = self.points[3] - self.points[0];
-                let u = glm::dot(&x.normalize(), &(point - self.points[0])) / glm::length(&x);
-                let v = glm::dot(&y.normalize(), &(point - self.points[0])) / glm::length(&y);
-                let uv = glm::vec2(u, v);
-                Some(RayHit {
-                    t,
-                    point,
-                    normal,
-                    uv,
-                })
-            } else {
-                None
-            }
+        if denom.abs() <= 0.0001 {
+            return None;
+        }
+        let num = glm::dot(&(self.points[0] - r.origin), &normal);
+        let t = num / denom;
+        if self.contains(r.point_at(t)) && t > min && t < max {
+            Some(t)
         } else {
             None
         }
     }
 }
 
+impl Geometry for Plane {
+    fn intersection(&self, r: &Ray, min: f32, max: f32) -> Option<RayHit> {
+        let t = self.distance(r, min, max)?;
+        let point = r.point_at(t);
+        let x = self.points[1] - self.points[0];
+        let y = self.points[3] - self.points[0];
+        let u = glm::dot(&x.normalize(), &(point - self.points[0])) / glm::length(&x);
+        let v = glm::dot(&y.normalize(), &(point - self.points[0])) / glm::length(&y);
+        let uv = glm::vec2(u, v);
+        Some(RayHit {
+            t,
+            point,
+            normal: self.normal(),
+            geometric_normal: self.normal(),
+            uv,
+            time: r.time,
+        })
+    }
+
+    fn occluded(&self, r: &Ray, min: f32, max: f32) -> bool {
+        self.distance(r, min, max).is_some()
+    }
+}
+
 impl Bounds for Plane {
     fn bounds(&self) -> AABB {
         AABB::from(self.points.iter())
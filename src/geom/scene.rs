@@ -1,26 +1,257 @@
-use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
 
 use super::*;
 use crate::ray::Ray;
 use crate::texture::ColorTexture;
 
-#[derive(Deserialize)]
 pub struct Scene {
     objects: Vec<Object>,
+    /// Tree over the bounds of the objects, used to find the ones a ray may hit
+    tree: Accel<ObjectBounds>,
+    /// Kind of acceleration structure built over the objects, and over meshes by default
+    accel: AccelType,
+    kd_tree: KdTreeConfig,
+    /// Indices of the emissive objects
+    lights: Vec<usize>,
     pub environment: ColorTexture,
 }
 
+/// A point sampled on one of the scene's lights.
+pub struct LightSample<'a> {
+    pub object: &'a Object,
+    pub surface: SurfaceSample,
+}
+
+impl Scene {
+    /// Builds the scene over `objects`, whose meshes must have been built already.
+    pub fn new(
+        objects: Vec<Object>,
+        environment: ColorTexture,
+        accel: AccelType,
+        kd_tree: KdTreeConfig,
+    ) -> Self {
+        let lights = find_lights(&objects);
+        let tree = build_tree(&objects, accel, &kd_tree);
+        Scene {
+            objects,
+            tree,
+            accel,
+            kd_tree,
+            lights,
+            environment,
+        }
+    }
+
+    /// Poses every animated object as it is at `frame`. Meshes and their trees are kept
+    /// as they are, only the tree over the objects is rebuilt if any of them may have moved.
+    pub fn set_frame(&mut self, frame: f32) {
+        let mut moved = false;
+        for object in &mut self.objects {
+            object.set_frame(frame);
+            moved |= object.has_transform_keys();
+        }
+        if moved {
+            self.tree = build_tree(&self.objects, self.accel, &self.kd_tree);
+        }
+        // Animated emission may turn lights on or off
+        self.lights = find_lights(&self.objects);
+    }
+
+    /// Picks a light using `select`, then samples a point on it as seen from `origin` at `time`.
+    /// The returned pdf accounts for the light selection.
+    pub fn sample_light(
+        &self,
+        origin: &Vec3,
+        time: f32,
+        select: f32,
+        u: Vec2,
+    ) -> Option<LightSample<'_>> {
+        let count = self.lights.len();
+        if count == 0 {
+            return None;
+        }
+        let idx = usize::min((select * count as f32) as usize, count - 1);
+        let object = &self.objects[self.lights[idx]];
+        let mut surface = object.sample_towards(origin, time, u)?;
+        surface.pdf /= count as f32;
+        Some(LightSample { object, surface })
+    }
+
+    /// Density of `sample_light` returning the point `hit` on `object`.
+    pub fn light_pdf(&self, origin: &Vec3, object: &Object, hit: &RayHit) -> f32 {
+        if object.material.is_emissive() {
+            object.pdf_towards(origin, hit) / self.lights.len() as f32
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Bounds of the object at `index` in the scene's object list.
+#[derive(Clone)]
+struct ObjectBounds {
+    index: usize,
+    bounds: AABB,
+}
+
+impl Bounds for ObjectBounds {
+    fn bounds(&self) -> AABB {
+        self.bounds.clone()
+    }
+}
+
+fn build_tree(objects: &[Object], accel: AccelType, kd_tree: &KdTreeConfig) -> Accel<ObjectBounds> {
+    let bounds = objects
+        .iter()
+        .enumerate()
+        .map(|(index, object)| ObjectBounds {
+            index,
+            bounds: object.bounds(),
+        })
+        .collect();
+    accel.build(bounds, kd_tree)
+}
+
+fn find_lights(objects: &[Object]) -> Vec<usize> {
+    objects
+        .iter()
+        .enumerate()
+        .filter(|(_, obj)| obj.material.is_emissive())
+        .map(|(i, _)| i)
+        .collect()
+}
+
 impl Traceable for Scene {
-    fn trace(&self, ray: &Ray, min: f32, max: f32) -> Option<TraceResult> {
-        let mut max = max;
-        let mut result = None;
-        for obj in &self.objects {
-            let traced = obj.trace(ray, min, max);
-            if let Some(TraceResult { hit, .. }) = &traced {
-                max = hit.t;
-                result = traced;
+    fn trace(&self, ray: &Ray, min: f32, max: f32) -> Option<TraceResult<'_>> {
+        self.tree.traverse(ray, min, max, &mut |object, min, max| {
+            let traced = self.objects[object.index].trace(ray, min, max)?;
+            let traced = TraceResult {
+                index: object.index,
+                ..traced
+            };
+            Some((traced.hit.t, traced))
+        })
+    }
+
+    fn occluded(&self, ray: &Ray, min: f32, max: f32) -> bool {
+        self.tree.any(ray, min, max, &mut |object, min, max| {
+            self.objects[object.index].occluded(ray, min, max)
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Scene {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct SceneDesc {
+            objects: Vec<Object>,
+            environment: ColorTexture,
+            /// Meshes shared by name between objects
+            #[serde(default)]
+            meshes: HashMap<String, Mesh>,
+            /// Acceleration structure used unless a mesh asks for another one
+            #[serde(default)]
+            accel: AccelType,
+            #[serde(default)]
+            kd_tree: KdTreeConfig,
+        }
+        let SceneDesc {
+            mut objects,
+            environment,
+            meshes,
+            accel,
+            kd_tree,
+        } = SceneDesc::deserialize(deserializer)?;
+        let build = |mesh: &mut Mesh| {
+            mesh.build(accel, &kd_tree).map_err(|e| {
+                D::Error::custom(format!("Could not load {}: {}", mesh.path().display(), e))
+            })
+        };
+        let meshes = meshes
+            .into_iter()
+            .map(|(name, mut mesh)| {
+                build(&mut mesh)?;
+                Ok((name, Arc::new(mesh)))
+            })
+            .collect::<Result<_, D::Error>>()?;
+        for object in &mut objects {
+            if object.motion.is_some() && object.has_transform_keys() {
+                return Err(D::Error::custom(
+                    "Objects with transform keyframes can't also set motion",
+                ));
+            }
+            match &mut object.geometry {
+                GeomType::Mesh(mesh) => build(mesh)?,
+                GeomType::Instance(instance) => {
+                    instance.resolve(&meshes).map_err(D::Error::custom)?
+                }
+                _ => (),
             }
         }
-        result
+        Ok(Scene::new(objects, environment, accel, kd_tree))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vec::glm;
+
+    const SPHERES: &str = "
+        environment = [0, 0, 0]
+        [[objects]]
+        geometry = { center = [0, 0, 0], radius = 1 }
+        material = { albedo = [1, 1, 1], metalness = 0, roughness = 1 }
+        [[objects]]
+        geometry = { center = [0, 0, 4], radius = 1 }
+        material = { albedo = [1, 1, 1], metalness = 0, roughness = 1 }
+        [[objects]]
+        geometry = { center = [0, 0, 8], radius = 1 }
+        material = { albedo = [1, 1, 1], metalness = 0, roughness = 1 }
+    ";
+
+    #[test]
+    fn traced_objects_know_their_index() {
+        let scene: Scene = toml::from_str(SPHERES).unwrap();
+        for (i, z) in [0.0, 4.0, 8.0].iter().enumerate() {
+            let ray = Ray::new(glm::vec3(0.0, 5.0, *z), glm::vec3(0.0, -1.0, 0.0));
+            let result = scene.trace(&ray, 0.0, f32::MAX).unwrap();
+            assert_eq!(result.index, i);
+            assert!(std::ptr::eq(result.object, &scene.objects[i]));
+        }
+    }
+
+    #[test]
+    fn rejects_motion_with_transform_keys() {
+        let scene = "
+            environment = [0, 0, 0]
+            [[objects]]
+            geometry = { center = [0, 0, 0], radius = 1 }
+            material = { albedo = [1, 1, 1], metalness = 0, roughness = 1 }
+            motion = { start = {}, end = { translate = [1, 0, 0] } }
+            keyframes = [
+                { frame = 0, transform = { translate = [0, 0, 0] } },
+                { frame = 1, transform = { translate = [0, 1, 0] } },
+            ]
+        ";
+        assert!(toml::from_str::<Scene>(scene).is_err());
+    }
+
+    #[test]
+    fn keeps_the_tree_when_nothing_moves() {
+        let keys = "
+            keyframes = [
+                { frame = 0, albedo = [1, 0, 0] },
+                { frame = 1, albedo = [0, 0, 1] },
+            ]
+        ";
+        let mut scene: Scene = toml::from_str(&(SPHERES.to_owned() + keys)).unwrap();
+        let items = scene.tree.items().as_ptr();
+        scene.set_frame(0.5);
+        assert_eq!(scene.tree.items().as_ptr(), items);
     }
 }
@@ -4,64 +4,136 @@ use serde::{Deserialize, Serialize};
 use super::*;
 
 use crate::ray::Ray;
+use crate::vec::{spherical_to_local, transform_to_world};
 use crate::Vec3;
 
 #[derive(Serialize, Deserialize)]
 pub struct Sphere {
     pub center: Vec3,
     pub radius: f32,
+    /// Distance the center moves over a frame
+    #[serde(default = "glm::zero")]
+    pub velocity: Vec3,
 }
 
-impl Geometry for Sphere {
-    fn intersection(&self, r: &Ray, min: f32, max: f32) -> Option<RayHit> {
-        let oc = r.origin - self.center;
+impl Sphere {
+    /// Distance along `r` to the nearest point on the sphere between `min` and `max`.
+    fn distance(&self, r: &Ray, center: &Vec3, min: f32, max: f32) -> Option<f32> {
+        let oc = r.origin - center;
         let a = glm::dot(&r.direction, &r.direction);
         let b = glm::dot(&r.direction, &oc);
         let c = glm::dot(&oc, &oc) - self.radius * self.radius;
         let delta = b * b - a * c;
-        if delta > 0.0 {
-            let t = (-b - f32::sqrt(b * b - a * c)) / a;
-            if t > min && t < max {
-                let point = r.point_at(t);
-                let normal = (point - self.center) / self.radius;
-                let uv = Self::uv_at_dir(&(point - self.center).normalize());
-                return Some(RayHit {
-                    t,
-                    point,
-                    normal,
-                    uv,
-                });
-            }
-            let t = (-b + f32::sqrt(b * b - a * c)) / a;
-            if t > min && t < max {
-                let point = r.point_at(t);
-                let normal = (point - self.center) / self.radius;
-                let uv = Self::uv_at_dir(&(point - self.center).normalize());
-                Some(RayHit {
-                    t,
-                    point,
-                    normal,
-                    uv,
-                })
-            } else {
-                None
-            }
-        } else {
-            None
+        if delta <= 0.0 {
+            return None;
         }
+        let near = (-b - f32::sqrt(delta)) / a;
+        let far = (-b + f32::sqrt(delta)) / a;
+        [near, far].iter().copied().find(|&t| t > min && t < max)
+    }
+}
+
+impl Geometry for Sphere {
+    fn intersection(&self, r: &Ray, min: f32, max: f32) -> Option<RayHit> {
+        let center = self.center_at(r.time);
+        let t = self.distance(r, &center, min, max)?;
+        let point = r.point_at(t);
+        let normal = (point - center) / self.radius;
+        let uv = Self::uv_at_dir(&(point - center).normalize());
+        Some(RayHit {
+            t,
+            point,
+            normal,
+            geometric_normal: normal,
+            uv,
+            time: r.time,
+        })
+    }
+
+    fn occluded(&self, r: &Ray, min: f32, max: f32) -> bool {
+        self.distance(r, &self.center_at(r.time), min, max)
+            .is_some()
     }
 }
 
 impl Bounds for Sphere {
+    /// Bounds covering the sphere over the whole frame.
     fn bounds(&self) -> AABB {
         let r_vec = glm::vec3(self.radius, self.radius, self.radius);
-        let min = self.center - r_vec;
-        let max = self.center + r_vec;
+        let end = self.center_at(1.0);
+        let min = glm::min2(&self.center, &end) - r_vec;
+        let max = glm::max2(&self.center, &end) + r_vec;
         AABB { min, max }
     }
 }
 
+impl Emitter for Sphere {
+    fn sample_towards(&self, origin: &Vec3, time: f32, u: Vec2) -> Option<SurfaceSample> {
+        let center = self.center_at(time);
+        let to_center = center - origin;
+        let dist2 = glm::dot(&to_center, &to_center);
+        let r2 = self.radius * self.radius;
+        if dist2 <= r2 {
+            // Inside the sphere: sample the whole surface uniformly
+            let dir = uniform_sphere(u);
+            let point = center + dir * self.radius;
+            let pdf = 1.0 / self.area();
+            return Some(SurfaceSample {
+                point,
+                normal: dir,
+                uv: Self::uv_at_dir(&dir),
+                pdf: area_to_solid_angle(pdf, origin, &point, &dir),
+            });
+        }
+        // Sample the cone of directions subtended by the sphere
+        let cos_max = f32::sqrt(f32::max(0.0, 1.0 - r2 / dist2));
+        let cos_theta = 1.0 - u.x * (1.0 - cos_max);
+        let theta = f32::acos(cos_theta);
+        let phi = u.y * glm::two_pi::<f32>();
+        let axis = to_center / f32::sqrt(dist2);
+        let dir = transform_to_world(&spherical_to_local(theta, phi), &axis);
+        let ray = Ray::new(*origin, dir).with_time(time);
+        let hit = self.intersection(&ray, 0.0, f32::MAX)?;
+        Some(SurfaceSample {
+            point: hit.point,
+            normal: hit.geometric_normal,
+            uv: hit.uv,
+            pdf: cone_pdf(cos_max),
+        })
+    }
+
+    fn pdf_towards(&self, origin: &Vec3, hit: &RayHit) -> f32 {
+        let to_center = self.center_at(hit.time) - origin;
+        let dist2 = glm::dot(&to_center, &to_center);
+        let r2 = self.radius * self.radius;
+        if dist2 <= r2 {
+            area_to_solid_angle(1.0 / self.area(), origin, &hit.point, &hit.geometric_normal)
+        } else {
+            cone_pdf(f32::sqrt(f32::max(0.0, 1.0 - r2 / dist2)))
+        }
+    }
+}
+
+fn cone_pdf(cos_max: f32) -> f32 {
+    1.0 / (glm::two_pi::<f32>() * (1.0 - cos_max))
+}
+
+fn uniform_sphere(u: Vec2) -> Vec3 {
+    let z = 1.0 - 2.0 * u.x;
+    let r = f32::sqrt(f32::max(0.0, 1.0 - z * z));
+    let phi = u.y * glm::two_pi::<f32>();
+    glm::vec3(r * f32::cos(phi), r * f32::sin(phi), z)
+}
+
 impl Sphere {
+    pub fn center_at(&self, time: f32) -> Vec3 {
+        self.center + self.velocity * time
+    }
+
+    pub fn area(&self) -> f32 {
+        2.0 * glm::two_pi::<f32>() * self.radius * self.radius
+    }
+
     pub fn uv_at_dir(dir: &Vec3) -> Vec2 {
         let u = 0.5 + f32::atan2(dir.z, dir.x) / glm::two_pi::<f32>();
         let v = 0.5 - f32::asin(dir.y) / glm::pi::<f32>();
@@ -0,0 +1,322 @@
+use serde::{Deserialize, Deserializer};
+
+use super::*;
+use crate::ray::Ray;
+use crate::vec::{glm, Vec3};
+
+/// Scale, rotation and translation of an object, applied in that order.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Transform {
+    pub translate: Vec3,
+    /// Rotation around the x, y and z axes in degrees, applied in that order
+    pub rotate: Vec3,
+    pub scale: Vec3,
+    /// Rows of an affine matrix used instead of the other components, if given
+    #[serde(deserialize_with = "matrix_rows")]
+    pub matrix: Option<glm::Mat4>,
+}
+
+fn matrix_rows<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<glm::Mat4>, D::Error> {
+    let rows = Option::<[[f32; 4]; 4]>::deserialize(deserializer)?;
+    Ok(rows.map(|rows| glm::Mat4::from_fn(|i, j| rows[i][j])))
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Transform {
+            translate: glm::zero(),
+            rotate: glm::zero(),
+            scale: glm::vec3(1.0, 1.0, 1.0),
+            matrix: None,
+        }
+    }
+}
+
+impl Transform {
+    /// Interpolates every component separately, so rotations can exceed half a turn.
+    /// If either side is given as a matrix, the matrices are interpolated element-wise.
+    pub fn lerp(&self, other: &Transform, t: f32) -> Transform {
+        if self.matrix.is_some() || other.matrix.is_some() {
+            return Transform {
+                matrix: Some(self.matrix() * (1.0 - t) + other.matrix() * t),
+                ..Transform::default()
+            };
+        }
+        Transform {
+            translate: glm::lerp(&self.translate, &other.translate, t),
+            rotate: glm::lerp(&self.rotate, &other.rotate, t),
+            scale: glm::lerp(&self.scale, &other.scale, t),
+            matrix: None,
+        }
+    }
+
+    pub fn matrix(&self) -> glm::Mat4 {
+        if let Some(matrix) = self.matrix {
+            return matrix;
+        }
+        let rotate = self.rotate.map(f32::to_radians);
+        let rotation = glm::rotation(rotate.z, &glm::vec3(0.0, 0.0, 1.0))
+            * glm::rotation(rotate.y, &glm::vec3(0.0, 1.0, 0.0))
+            * glm::rotation(rotate.x, &glm::vec3(1.0, 0.0, 0.0));
+        glm::translation(&self.translate) * rotation * glm::scaling(&self.scale)
+    }
+}
+
+/// Movement of an object over a frame, from `start` at time 0 to `end` at time 1.
+#[derive(Deserialize)]
+pub struct Motion {
+    pub start: Transform,
+    pub end: Transform,
+}
+
+impl Motion {
+    pub fn at(&self, time: f32) -> Affine {
+        Affine::new(self.start.lerp(&self.end, time).matrix())
+    }
+
+    /// Bounds of `local` over the whole frame.
+    pub fn bounds(&self, local: &AABB) -> AABB {
+        let start = Affine::new(self.start.matrix()).bounds(local);
+        let end = Affine::new(self.end.matrix()).bounds(local);
+        let blended = self.start.matrix.is_some() || self.end.matrix.is_some();
+        if blended || self.start.rotate == self.end.rotate {
+            // Every point moves linearly, so it stays between its start and end positions
+            return start.union(&end);
+        }
+        // Rotation keeps the distance to the origin, which only scaling changes, and
+        // scaling is largest at either end, so a capsule along the translation covers it
+        let corners = local.corners();
+        let radius = corners
+            .iter()
+            .flat_map(|c| {
+                let s = &self.start.scale;
+                let e = &self.end.scale;
+                vec![
+                    glm::length(&c.component_mul(s)),
+                    glm::length(&c.component_mul(e)),
+                ]
+            })
+            .fold(0.0, f32::max);
+        let r = glm::vec3(radius, radius, radius);
+        let (min, max) = (
+            glm::min2(&self.start.translate, &self.end.translate),
+            glm::max2(&self.start.translate, &self.end.translate),
+        );
+        AABB {
+            min: min - r,
+            max: max + r,
+        }
+    }
+}
+
+/// An affine transform from object to world space, along with its inverse.
+#[derive(Deserialize, Clone)]
+#[serde(from = "Transform")]
+pub struct Affine {
+    matrix: glm::Mat4,
+    inverse: glm::Mat4,
+}
+
+impl Affine {
+    pub fn new(matrix: glm::Mat4) -> Self {
+        Affine {
+            matrix,
+            inverse: glm::inverse(&matrix),
+        }
+    }
+
+    /// The transform applying `inner` first and `self` after it.
+    pub fn compose(&self, inner: &Affine) -> Affine {
+        Affine {
+            matrix: self.matrix * inner.matrix,
+            inverse: inner.inverse * self.inverse,
+        }
+    }
+
+    pub fn point(&self, p: &Vec3) -> Vec3 {
+        (self.matrix * glm::vec4(p.x, p.y, p.z, 1.0)).xyz()
+    }
+
+    pub fn inverse_point(&self, p: &Vec3) -> Vec3 {
+        (self.inverse * glm::vec4(p.x, p.y, p.z, 1.0)).xyz()
+    }
+
+    fn inverse_vector(&self, v: &Vec3) -> Vec3 {
+        (self.inverse * glm::vec4(v.x, v.y, v.z, 0.0)).xyz()
+    }
+
+    /// World space normal of a surface with normal `n` in object space.
+    pub fn normal(&self, n: &Vec3) -> Vec3 {
+        glm::normalize(&(glm::transpose(&self.inverse) * glm::vec4(n.x, n.y, n.z, 0.0)).xyz())
+    }
+
+    /// Object space normal of a surface with normal `n` in world space.
+    pub fn inverse_normal(&self, n: &Vec3) -> Vec3 {
+        glm::normalize(&(glm::transpose(&self.matrix) * glm::vec4(n.x, n.y, n.z, 0.0)).xyz())
+    }
+
+    /// `ray` in object space. Directions are not renormalized, so distances along the ray
+    /// are the same in both spaces.
+    pub fn to_local(&self, ray: &Ray) -> Ray {
+        Ray::new(
+            self.inverse_point(&ray.origin),
+            self.inverse_vector(&ray.direction),
+        )
+        .with_time(ray.time)
+    }
+
+    /// Factor by which surface areas with object space normal `n` grow in world space.
+    fn area_scale(&self, n: &Vec3) -> f32 {
+        let linear = glm::mat4_to_mat3(&self.matrix);
+        let n = glm::normalize(n);
+        let cofactor = glm::transpose(&glm::inverse(&linear)) * n;
+        f32::abs(glm::determinant(&linear)) * glm::length(&cofactor)
+    }
+
+    /// Converts the solid angle density of sampling `local` from `local_origin` in
+    /// object space into the density of sampling `world` from `origin` in world space.
+    pub fn solid_angle_pdf(
+        &self,
+        pdf: f32,
+        local_origin: &Vec3,
+        local: (&Vec3, &Vec3),
+        origin: &Vec3,
+        world: (&Vec3, &Vec3),
+    ) -> f32 {
+        let (local_point, local_normal) = local;
+        let area_pdf = solid_angle_to_area(pdf, local_origin, local_point, local_normal)
+            / self.area_scale(local_normal);
+        area_to_solid_angle(area_pdf, origin, world.0, world.1)
+    }
+
+    pub fn bounds(&self, local: &AABB) -> AABB {
+        let corners = local
+            .corners()
+            .iter()
+            .map(|c| self.point(c))
+            .collect::<Vec<_>>();
+        AABB::from(corners.iter())
+    }
+}
+
+impl From<Transform> for Affine {
+    fn from(transform: Transform) -> Self {
+        Affine::new(transform.matrix())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: &Vec3, b: &Vec3) -> bool {
+        glm::distance(a, b) < 1e-4
+    }
+
+    fn transform(translate: Vec3, rotate: Vec3, scale: Vec3) -> Transform {
+        Transform {
+            translate,
+            rotate,
+            scale,
+            matrix: None,
+        }
+    }
+
+    fn skewed() -> Affine {
+        Affine::from(transform(
+            glm::vec3(1.0, -2.0, 3.0),
+            glm::vec3(30.0, 45.0, 60.0),
+            glm::vec3(2.0, 0.5, 1.5),
+        ))
+    }
+
+    #[test]
+    fn components_apply_scale_rotation_then_translation() {
+        let affine = Affine::from(transform(
+            glm::vec3(0.0, 0.0, 5.0),
+            glm::vec3(0.0, 0.0, 90.0),
+            glm::vec3(2.0, 2.0, 2.0),
+        ));
+        let p = affine.point(&glm::vec3(1.0, 0.0, 0.0));
+        assert!(close(&p, &glm::vec3(0.0, 2.0, 5.0)));
+    }
+
+    #[test]
+    fn inverse_undoes_the_transform() {
+        let affine = skewed();
+        let p = glm::vec3(0.3, -1.2, 4.0);
+        assert!(close(&affine.inverse_point(&affine.point(&p)), &p));
+        assert!(close(&affine.point(&affine.inverse_point(&p)), &p));
+    }
+
+    #[test]
+    fn compose_applies_inner_first() {
+        let outer = skewed();
+        let inner = Affine::from(transform(
+            glm::vec3(-1.0, 0.0, 2.0),
+            glm::vec3(0.0, 90.0, 0.0),
+            glm::vec3(1.0, 3.0, 1.0),
+        ));
+        let both = outer.compose(&inner);
+        let p = glm::vec3(0.5, 0.25, -2.0);
+        assert!(close(&both.point(&p), &outer.point(&inner.point(&p))));
+        assert!(close(
+            &both.inverse_point(&p),
+            &inner.inverse_point(&outer.inverse_point(&p))
+        ));
+    }
+
+    #[test]
+    fn normals_stay_perpendicular_under_nonuniform_scale() {
+        let affine = skewed();
+        let n = glm::normalize(&glm::vec3(1.0, 1.0, 0.0));
+        let tangent = glm::vec3(1.0, -1.0, 0.0);
+        let world_tangent = affine.point(&tangent) - affine.point(&glm::zero());
+        let world_normal = affine.normal(&n);
+        assert!(glm::dot(&world_normal, &world_tangent).abs() < 1e-4);
+        assert!((glm::length(&world_normal) - 1.0).abs() < 1e-6);
+        assert!(close(&affine.inverse_normal(&world_normal), &n));
+    }
+
+    #[test]
+    fn local_rays_keep_distances() {
+        let affine = skewed();
+        let ray = Ray::new(glm::vec3(0.0, 1.0, -3.0), glm::vec3(0.0, 0.0, 1.0));
+        let local = affine.to_local(&ray);
+        for &t in &[0.0, 1.0, 2.5] {
+            assert!(close(&affine.point(&local.point_at(t)), &ray.point_at(t)));
+        }
+    }
+
+    #[test]
+    fn area_scale_of_uniform_and_axis_scaling() {
+        let uniform = Affine::new(glm::scaling(&glm::vec3(2.0, 2.0, 2.0)));
+        assert!((uniform.area_scale(&glm::vec3(0.0, 1.0, 0.0)) - 4.0).abs() < 1e-5);
+        // A plane facing up only stretches along x and z
+        let stretched = Affine::new(glm::scaling(&glm::vec3(3.0, 5.0, 2.0)));
+        assert!((stretched.area_scale(&glm::vec3(0.0, 1.0, 0.0)) - 6.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn motion_bounds_cover_every_time() {
+        let motion = Motion {
+            start: transform(glm::zero(), glm::zero(), glm::vec3(1.0, 1.0, 1.0)),
+            end: transform(
+                glm::vec3(4.0, 1.0, 0.0),
+                glm::vec3(0.0, 180.0, 0.0),
+                glm::vec3(2.0, 2.0, 2.0),
+            ),
+        };
+        let local = AABB {
+            min: glm::vec3(-1.0, -1.0, -1.0),
+            max: glm::vec3(1.0, 1.0, 1.0),
+        };
+        let bounds = motion.bounds(&local);
+        for i in 0..=10 {
+            let moved = motion.at(i as f32 / 10.0).bounds(&local);
+            assert!(moved.min.iter().zip(bounds.min.iter()).all(|(m, b)| m >= b));
+            assert!(moved.max.iter().zip(bounds.max.iter()).all(|(m, b)| m <= b));
+        }
+    }
+}
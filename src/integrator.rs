@@ -0,0 +1,134 @@
+mod ao;
+mod debug;
+mod direct;
+mod path;
+
+use serde::Deserialize;
+
+pub use self::ao::*;
+pub use self::debug::*;
+pub use self::direct::*;
+pub use self::path::*;
+
+use crate::config::RenderParams;
+use crate::geom::*;
+use crate::material::Material;
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::texture::Texture as _;
+use crate::vec::{glm, Vec3};
+
+const EPSILON: f32 = 0.001;
+
+pub trait Integrator: Sync {
+    /// Radiance arriving at the origin of `ray` from its direction.
+    fn radiance(&self, ray: &Ray, scene: &Scene, sampler: &mut dyn Sampler) -> Vec3;
+
+    /// Whether the output is radiance that should go through exposure, tonemapping and
+    /// display encoding, rather than values written to 8 bit images as they are.
+    fn tonemapped(&self) -> bool {
+        true
+    }
+
+    /// Whether the output only carries a single meaningful channel.
+    fn grayscale(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum IntegratorType {
+    Path,
+    Direct,
+    AmbientOcclusion,
+    Normals,
+    Albedo,
+}
+
+impl IntegratorType {
+    pub fn build(self, params: &RenderParams) -> Box<dyn Integrator> {
+        match self {
+            IntegratorType::Path => Box::new(PathTracer {
+                max_depth: params.max_light_bounces,
+                rr_depth: params.russian_roulette_depth,
+            }),
+            IntegratorType::Direct => Box::new(DirectLighting),
+            IntegratorType::AmbientOcclusion => Box::new(AmbientOcclusion {
+                samples: params.ao_samples,
+                distance: params.ao_distance,
+            }),
+            IntegratorType::Normals => Box::new(Debug {
+                mode: DebugMode::Normals,
+            }),
+            IntegratorType::Albedo => Box::new(Debug {
+                mode: DebugMode::Albedo,
+            }),
+        }
+    }
+}
+
+/// Weight of a sample drawn with density `pdf` when combined with one drawn with `other`.
+fn power_heuristic(pdf: f32, other: f32) -> f32 {
+    let (a, b) = (pdf * pdf, other * other);
+    if a + b > 0.0 {
+        a / (a + b)
+    } else {
+        0.0
+    }
+}
+
+fn environment(scene: &Scene, ray: &Ray) -> Vec3 {
+    let dir = ray.direction.normalize();
+    scene.environment.sample(Sphere::uv_at_dir(&dir))
+}
+
+/// Light emitted towards the origin of `ray` by the surface it hit.
+/// `bsdf_pdf` is the density with which `ray` was sampled, or `None` for camera rays.
+fn emitted(scene: &Scene, ray: &Ray, object: &Object, hit: &RayHit, bsdf_pdf: Option<f32>) -> Vec3 {
+    let material = &object.material;
+    let emission = material.emission.sample(hit.uv);
+    match bsdf_pdf {
+        // Light sampling at the previous hit could also have found this point
+        Some(pdf) if material.is_emissive() => {
+            emission * power_heuristic(pdf, scene.light_pdf(&ray.origin, object, hit))
+        }
+        _ => emission,
+    }
+}
+
+/// Radiance reaching `hit` directly from a sampled point on one of the scene's lights.
+fn sample_direct(
+    scene: &Scene,
+    material: &Material,
+    hit: &RayHit,
+    w0: &Vec3,
+    sampler: &mut dyn Sampler,
+) -> Vec3 {
+    let select = sampler.get_1d();
+    let u = sampler.get_2d();
+    let sample = scene.sample_light(&hit.point, hit.time, select, u);
+    let LightSample { object, surface } = match sample {
+        Some(sample) if sample.surface.pdf > 0.0 => sample,
+        _ => return glm::zero(),
+    };
+    let to_light = surface.point - hit.point;
+    let dist = glm::length(&to_light);
+    if dist <= EPSILON {
+        // Sampled the shading point itself, e.g. on the light being shaded
+        return glm::zero();
+    }
+    let wi = to_light / dist;
+    let costheta = glm::dot(&hit.normal, &wi);
+    if costheta <= 0.0 {
+        return glm::zero();
+    }
+    let shadow = hit.spawn(wi);
+    if scene.occluded(&shadow, EPSILON, dist - EPSILON) {
+        return glm::zero();
+    }
+    let f = material.eval(w0, &wi, &hit.normal, hit.uv);
+    let weight = power_heuristic(surface.pdf, material.pdf(w0, &wi, &hit.normal, hit.uv));
+    let emission = object.material.emission.sample(surface.uv);
+    f.component_mul(&emission) * costheta * weight / surface.pdf
+}
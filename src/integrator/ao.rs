@@ -0,0 +1,36 @@
+use super::*;
+use crate::vec::cosine_hemisphere;
+
+/// Fraction of the hemisphere around the first hit that is not blocked by nearby geometry.
+pub struct AmbientOcclusion {
+    /// Number of occlusion rays per camera ray
+    pub samples: usize,
+    /// Geometry further away than this does not occlude
+    pub distance: f32,
+}
+
+impl Integrator for AmbientOcclusion {
+    fn radiance(&self, ray: &Ray, scene: &Scene, sampler: &mut dyn Sampler) -> Vec3 {
+        let hit = match scene.trace(ray, EPSILON, f32::MAX) {
+            Some(TraceResult { hit, .. }) => hit,
+            None => return glm::vec3(1.0, 1.0, 1.0),
+        };
+        let unoccluded = (0..self.samples)
+            .filter(|_| {
+                let dir = cosine_hemisphere(&hit.normal, sampler.get_2d());
+                let ray = hit.spawn(dir);
+                !scene.occluded(&ray, EPSILON, self.distance)
+            })
+            .count();
+        let visibility = unoccluded as f32 / self.samples as f32;
+        glm::vec3(visibility, visibility, visibility)
+    }
+
+    fn tonemapped(&self) -> bool {
+        false
+    }
+
+    fn grayscale(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,31 @@
+use super::*;
+
+#[derive(Clone, Copy)]
+pub enum DebugMode {
+    /// World space normal, remapped to [0, 1]
+    Normals,
+    /// Surface albedo without any lighting
+    Albedo,
+}
+
+/// Shows a surface property at the first hit, for quick previews.
+pub struct Debug {
+    pub mode: DebugMode,
+}
+
+impl Integrator for Debug {
+    fn radiance(&self, ray: &Ray, scene: &Scene, _sampler: &mut dyn Sampler) -> Vec3 {
+        let TraceResult { object, hit, .. } = match scene.trace(ray, EPSILON, f32::MAX) {
+            Some(result) => result,
+            None => return glm::zero(),
+        };
+        match self.mode {
+            DebugMode::Normals => (hit.normal.normalize() + glm::vec3(1.0, 1.0, 1.0)) * 0.5,
+            DebugMode::Albedo => object.material.albedo.sample(hit.uv),
+        }
+    }
+
+    fn tonemapped(&self) -> bool {
+        false
+    }
+}
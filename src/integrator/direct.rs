@@ -0,0 +1,32 @@
+use super::*;
+
+/// Only accounts for light reaching the first hit straight from an emitter or the environment.
+pub struct DirectLighting;
+
+impl Integrator for DirectLighting {
+    fn radiance(&self, ray: &Ray, scene: &Scene, sampler: &mut dyn Sampler) -> Vec3 {
+        let TraceResult { object, hit, .. } = match scene.trace(ray, EPSILON, f32::MAX) {
+            Some(result) => result,
+            None => return environment(scene, ray),
+        };
+        let material = &object.material;
+        let RayHit { normal, uv, .. } = hit;
+        let w0 = -ray.direction.normalize();
+        let mut radiance = emitted(scene, ray, object, &hit, None);
+        radiance += sample_direct(scene, material, &hit, &w0, sampler);
+
+        let (bounce, pdf) = material.bounce(&w0, &hit, sampler);
+        if pdf > 0.0 {
+            let incident = match scene.trace(&bounce, EPSILON, f32::MAX) {
+                Some(TraceResult { object, hit, .. }) => {
+                    emitted(scene, &bounce, object, &hit, Some(pdf))
+                }
+                None => environment(scene, &bounce),
+            };
+            let f = material.eval(&w0, &bounce.direction, &normal, uv);
+            let costheta = f32::max(glm::dot(&normal, &bounce.direction), 0.0);
+            radiance += f.component_mul(&incident) * costheta / pdf;
+        }
+        radiance
+    }
+}
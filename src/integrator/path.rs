@@ -0,0 +1,57 @@
+use super::*;
+
+/// Unidirectional path tracer with next-event estimation.
+pub struct PathTracer {
+    /// Maximum number of segments in a path
+    pub max_depth: usize,
+    /// Number of segments after which paths are terminated early with Russian roulette
+    pub rr_depth: usize,
+}
+
+impl Integrator for PathTracer {
+    fn radiance(&self, r: &Ray, scene: &Scene, sampler: &mut dyn Sampler) -> Vec3 {
+        let mut radiance = glm::zero();
+        let mut throughput = glm::vec3(1.0, 1.0, 1.0);
+        let mut ray = r.clone();
+        let mut bsdf_pdf = None;
+        for depth in 1..=self.max_depth {
+            let TraceResult { object, hit, .. } = match scene.trace(&ray, EPSILON, f32::MAX) {
+                Some(result) => result,
+                None => {
+                    radiance += throughput.component_mul(&environment(scene, &ray));
+                    break;
+                }
+            };
+            let emitted = emitted(scene, &ray, object, &hit, bsdf_pdf);
+            radiance += throughput.component_mul(&emitted);
+            if depth == self.max_depth {
+                break;
+            }
+
+            let material = &object.material;
+            let RayHit { normal, uv, .. } = hit;
+            let w0 = -ray.direction.normalize();
+            let direct = sample_direct(scene, material, &hit, &w0, sampler);
+            radiance += throughput.component_mul(&direct);
+
+            let (bounce, pdf) = material.bounce(&w0, &hit, sampler);
+            if pdf <= 0.0 {
+                break;
+            }
+            let f = material.eval(&w0, &bounce.direction, &normal, uv);
+            let costheta = f32::max(glm::dot(&normal, &bounce.direction), 0.0);
+            throughput = throughput.component_mul(&f) * costheta / pdf;
+
+            if depth >= self.rr_depth {
+                let survival = f32::min(glm::comp_max(&throughput), 0.95);
+                if sampler.get_1d() >= survival {
+                    break;
+                }
+                throughput /= survival;
+            }
+            ray = bounce;
+            bsdf_pdf = Some(pdf);
+        }
+        radiance
+    }
+}
@@ -0,0 +1,19 @@
+pub mod animation;
+pub mod camera;
+pub mod config;
+pub mod film;
+pub mod filter;
+pub mod geom;
+pub mod hash;
+pub mod integrator;
+pub mod material;
+pub mod obj;
+pub mod output;
+pub mod ray;
+pub mod rng;
+pub mod sampler;
+pub mod texture;
+pub mod tonemap;
+pub mod vec;
+
+use vec::*;
@@ -1,121 +1,259 @@
-mod camera;
-mod config;
-mod geom;
-mod material;
-mod obj;
-mod ray;
-mod texture;
-mod vec;
-
-use indicatif::{ParallelProgressIterator as _, ProgressBar, ProgressStyle};
-use rand::prelude::*;
+use indicatif::{ProgressBar, ProgressStyle};
 use rayon::prelude::*;
-use std::path::PathBuf;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::time::Instant;
-use vec::*;
-
-use config::UserConfig;
-use geom::*;
-use ray::Ray;
-use texture::Texture as _;
 
-fn trace(r: &Ray, scene: &Scene, depth: usize) -> Vec3 {
-    if depth == 0 {
-        return glm::zero();
-    }
-    if let Some(TraceResult { material, hit }) = scene.trace(r, 0.001, std::f32::MAX) {
-        let RayHit { normal, uv, .. } = hit;
-        let w0 = -r.direction;
-        let (bounce, pdf) = material.bounce(&w0, &hit);
-        let incident = trace(&bounce, scene, depth - 1);
-        let (brdf, ks) = material.brdf(&w0, &bounce.direction, &normal, uv);
-        let specular = brdf / pdf;
-        let diffuse = {
-            let lambert = material.albedo.sample(uv) / glm::pi::<f32>();
-            let kd = (glm::vec3(1.0, 1.0, 1.0) - ks) * (1.0 - material.metalness.sample(uv));
-            let pdf = glm::one_over_two_pi::<f32>();
-            kd.component_mul(&lambert) / pdf
-        };
-        let costheta = f32::max(glm::dot(&normal, &bounce.direction), 0.0);
-        (diffuse + specular).component_mul(&incident) * costheta + material.emission.sample(uv)
-    } else {
-        let dir = r.direction.normalize();
-        scene.environment.sample(Sphere::uv_at_dir(&dir))
-    }
-}
+use prayer::camera::Camera;
+use prayer::config::{RenderParams, UserConfig};
+use prayer::film::{Film, SampleBudget};
+use prayer::filter::PixelFilter;
+use prayer::geom::Scene;
+use prayer::hash;
+use prayer::integrator::Integrator;
+use prayer::output::{self, OutputConfig};
+use prayer::sampler::Sampler as _;
+use prayer::vec::*;
 
 fn quit_with_usage() -> ! {
-    eprintln!("Usage: prayer CONFIG [OUTPUT]");
+    eprintln!("Usage: prayer [--camera NAME] CONFIG [OUTPUT]");
     std::process::exit(1)
 }
 
 fn main() {
-    let mut args = std::env::args();
-    let config = args
-        .nth(1)
-        .map(PathBuf::from)
-        .unwrap_or_else(|| quit_with_usage());
-    let image = args
+    let mut camera_name = None;
+    let mut positional = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--camera" {
+            camera_name = Some(args.next().unwrap_or_else(|| quit_with_usage()));
+        } else {
+            positional.push(arg);
+        }
+    }
+    let mut positional = positional.into_iter();
+    let config = positional
         .next()
         .map(PathBuf::from)
-        .unwrap_or_else(|| config.with_extension("png"));
-    let UserConfig { params, scene } = UserConfig::from_file(&config).unwrap_or_else(|e| {
+        .unwrap_or_else(|| quit_with_usage());
+    // Views from named cameras get their own default output, e.g. `scene.top.png`
+    let image = positional.next().map(PathBuf::from).unwrap_or_else(|| {
+        let ext = match &camera_name {
+            Some(name) => format!("{}.png", name),
+            None => "png".to_owned(),
+        };
+        config.with_extension(ext)
+    });
+    let UserConfig {
+        params,
+        mut scene,
+        output,
+        camera,
+        cameras,
+        hash,
+    } = UserConfig::from_file(&config).unwrap_or_else(|e| {
         eprintln!("Could not parse scene file {}: {}", config.display(), e);
         std::process::exit(1)
     });
+    // Renders only resume from samples taken with the same config and view
+    let settings = hash::fnv1a_continue(hash, camera_name.as_deref().unwrap_or("").as_bytes());
+    let camera = match camera_name {
+        Some(name) => cameras.get(&name).unwrap_or_else(|| {
+            let mut names = cameras.keys().cloned().collect::<Vec<_>>();
+            names.sort();
+            eprintln!(
+                "No camera named {} in {}, available cameras: {}",
+                name,
+                config.display(),
+                names.join(", ")
+            );
+            std::process::exit(1)
+        }),
+        None => &camera,
+    };
 
     println!("Parsed scene file.");
+    let aspect = params.resolution.x as f32 / params.resolution.y as f32;
+    let integrator = params.integrator.build(&params);
+
+    let (first, last) = params.frames.map_or((0, 0), |frames| (frames.x, frames.y));
+    for frame in first..=last {
+        // Only the animated parts change, the scene is not rebuilt between frames
+        scene.set_frame(frame as f32);
+        let camera = camera.build(aspect, frame as f32);
+        let image = match params.frames {
+            Some(_) => {
+                println!(
+                    "Rendering frame {} of {}.",
+                    frame - first + 1,
+                    last - first + 1
+                );
+                frame_path(&image, frame)
+            }
+            None => image.clone(),
+        };
+        render(
+            &image,
+            settings,
+            &params,
+            &scene,
+            &camera,
+            integrator.as_ref(),
+            &output,
+        );
+    }
+}
+
+/// Path of the image for `frame` of a sequence, e.g. `out_0001.png` for `out.png`.
+fn frame_path(image: &Path, frame: u32) -> PathBuf {
+    let stem = image.file_stem().and_then(OsStr::to_str).unwrap_or("");
+    let name = match image.extension().and_then(OsStr::to_str) {
+        Some(ext) => format!("{}_{:04}.{}", stem, frame, ext),
+        None => format!("{}_{:04}", stem, frame),
+    };
+    image.with_file_name(name)
+}
+
+fn render(
+    image: &Path,
+    settings: u64,
+    params: &RenderParams,
+    scene: &Scene,
+    camera: &Camera,
+    integrator: &dyn Integrator,
+    output: &OutputConfig,
+) {
     let w = params.resolution.x;
     let h = params.resolution.y;
-    let camera = camera::Camera::looking_at(
-        params.camera_pos,
-        params.looking_at,
-        glm::vec3(0.0, 1.0, 0.0),
-        params.fov,
-        w as f32 / h as f32,
-    );
+
+    // Resume from an unfinished run with the same output and settings, if there is one
+    let accum = image.with_extension(format!(
+        "{}.accum",
+        image.extension().and_then(OsStr::to_str).unwrap_or("")
+    ));
+    let filter = PixelFilter::new(params.filter, params.filter_radius);
+    let mut film = match Film::load(&accum, settings, params.tile_size, filter) {
+        Ok(film) if film.width == w && film.height == h => {
+            println!("Resuming from {} samples per pixel.", film.min_samples());
+            film
+        }
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            println!("Not resuming from {}: {}", accum.display(), e);
+            Film::new(w, h, params.tile_size, filter)
+        }
+        _ => Film::new(w, h, params.tile_size, filter),
+    };
 
     let num_pixels = w * h;
-    let pb = ProgressBar::new(num_pixels.into());
+    let spp = params.samples_per_pass;
+    let budget = SampleBudget {
+        min: params.min_samples(),
+        max: params.samples,
+        threshold: params.adaptive_threshold,
+    };
+    let remaining_passes = params
+        .samples
+        .saturating_sub(film.min_samples())
+        .div_ceil(spp);
+    let pb = ProgressBar::new(u64::from(num_pixels) * remaining_passes as u64);
     pb.set_style(
         ProgressStyle::default_bar().template("Rendering... {bar:40} {percent}% (ETA: {eta})"),
     );
     pb.set_draw_delta(100);
 
+    let save_image = |film: &Film| {
+        let colors = film.pixels();
+        output::save_image(image, (w, h), &colors, params, integrator).unwrap_or_else(|e| {
+            eprintln!("Could not write image file to {}: {}", image.display(), e);
+            std::process::exit(1)
+        });
+    };
+    let checkpoint = |film: &Film| {
+        save_image(film);
+        film.save(&accum, settings).unwrap_or_else(|e| {
+            eprintln!(
+                "Could not write accumulation buffer to {}: {}",
+                accum.display(),
+                e
+            );
+        });
+    };
+
     let start = Instant::now();
-    let buffer = (0..num_pixels)
-        .into_par_iter()
-        .progress_with(pb)
-        .flat_map(|i| {
-            let x = i % w;
-            let y = i / w;
-            let color = (0..params.samples)
-                .into_par_iter()
-                .map(|_| {
-                    let mut rng = rand::thread_rng();
-                    let rand: f32 = rng.gen();
-                    let u = (x as f32 + rand) / w as f32;
-                    let rand: f32 = rng.gen();
-                    let v = (y as f32 + rand) / h as f32;
-                    let ray = camera.ray_at(u, v);
-                    trace(&ray, &scene, params.max_light_bounces)
-                })
-                .sum::<Vec3>()
-                / params.samples as f32;
-            let color = glm::vec3(1.0, 1.0, 1.0) - glm::exp(&(-color * params.exposure));
-            vec![
-                (color.x.max(0.0).min(1.0).powf(1.0 / params.gamma) * 255.99) as u8,
-                (color.y.max(0.0).min(1.0).powf(1.0 / params.gamma) * 255.99) as u8,
-                (color.z.max(0.0).min(1.0).powf(1.0 / params.gamma) * 255.99) as u8,
-            ]
-        })
-        .collect::<Vec<_>>();
+    let mut last_checkpoint = Instant::now();
+    let mut passes = 0;
+    loop {
+        let sampled = film.add_samples(spp, &budget, &pb, |x, y, index| {
+            let mut sampler = params
+                .sampler
+                .start(params.seed, (x, y), index, params.samples);
+            let offset = sampler.get_2d();
+            let u = (x as f32 + offset.x) / w as f32;
+            let v = (y as f32 + offset.y) / h as f32;
+            let lens = sampler.get_2d();
+            let radiance = match camera.sample_ray(u, v, lens, sampler.get_1d()) {
+                Some(ray) => integrator.radiance(&ray, scene, &mut sampler),
+                None => glm::zero(),
+            };
+            (offset, radiance)
+        });
+        if sampled == 0 {
+            break;
+        }
+        passes += 1;
+
+        let every_passes = params.checkpoint_passes > 0 && passes % params.checkpoint_passes == 0;
+        let every_seconds = params.checkpoint_seconds > 0
+            && last_checkpoint.elapsed().as_secs() >= params.checkpoint_seconds;
+        if every_passes || every_seconds {
+            checkpoint(&film);
+            last_checkpoint = Instant::now();
+        }
+    }
+    pb.finish_and_clear();
     let duration = start.elapsed();
     println!("Rendering complete. (runtime: {}s)", duration.as_secs());
 
-    image::save_buffer(&image, &buffer, w, h, image::RGB(8)).unwrap_or_else(|e| {
-        eprintln!("Could not write image file to {}: {}", image.display(), e);
-        std::process::exit(1)
-    });
+    save_image(&film);
     println!("Saved image.");
+    // Every sample is in the image now, so there is nothing left to resume
+    if let Err(e) = fs::remove_file(&accum) {
+        if e.kind() != io::ErrorKind::NotFound {
+            eprintln!(
+                "Could not remove accumulation buffer {}: {}",
+                accum.display(),
+                e
+            );
+        }
+    }
+
+    if output.any_pass() || output.layered {
+        let aovs = (0..num_pixels)
+            .into_par_iter()
+            .map(|i| {
+                let u = ((i % w) as f32 + 0.5) / w as f32;
+                let v = ((i / w) as f32 + 0.5) / h as f32;
+                match camera.ray_at(u, v) {
+                    Some(ray) => output::Aov::at(&ray, scene),
+                    None => output::Aov::empty(),
+                }
+            })
+            .collect::<Vec<_>>();
+        output
+            .write_passes(
+                image,
+                (w, h),
+                &film.pixels(),
+                &aovs,
+                &film.sample_counts(),
+                params.gamma,
+            )
+            .unwrap_or_else(|e| {
+                eprintln!("Could not write output passes: {}", e);
+                std::process::exit(1)
+            });
+        println!("Saved output passes.");
+    }
 }
@@ -1,32 +1,13 @@
 use nalgebra_glm as glm;
 use serde::Deserialize;
 
-use rand::prelude::*;
-
 use crate::geom::RayHit;
 use crate::ray::Ray;
+use crate::sampler::Sampler;
 use crate::texture::{ColorTexture, GrayScaleTexture, Texture as _};
+use crate::vec::{cosine_hemisphere, spherical_to_local, transform_to_world};
 use crate::{Vec2, Vec3};
 
-fn transform_to_world(vec: &Vec3, norm: &Vec3) -> Vec3 {
-    // Find an axis that is not parallel to normal
-    let major_axis = if f32::abs(norm.x) < (1.0 / f32::sqrt(3.0)) {
-        glm::vec3(1.0, 0.0, 0.0)
-    } else if f32::abs(norm.y) < (1.0 / f32::sqrt(3.0)) {
-        glm::vec3(0.0, 1.0, 0.0)
-    } else {
-        glm::vec3(0.0, 0.0, 1.0)
-    };
-
-    // Create a coordinate system relative to world space
-    let u = glm::normalize(&norm.cross(&major_axis));
-    let v = norm.cross(&u);
-    let w = norm;
-
-    // Transform from local coordinates to world coordinates
-    v * vec.x + w * vec.y + u * vec.z
-}
-
 #[derive(Deserialize)]
 pub struct Material {
     pub albedo: ColorTexture,
@@ -38,44 +19,76 @@ pub struct Material {
 }
 
 impl Material {
-    fn importance_theta(&self, roughness: f32) -> f32 {
-        let mut rng = rand::thread_rng();
+    fn importance_theta(&self, roughness: f32, eta: f32) -> f32 {
         let a = roughness * roughness;
-        let eta: f32 = rng.gen();
         let sqrt = f32::sqrt(eta / (1.0 - eta));
         f32::atan(a * sqrt)
     }
 
-    pub fn bounce(&self, w0: &Vec3, hit: &RayHit) -> (Ray, f32) {
-        let n = hit.normal;
-        let mut rng = rand::thread_rng();
-        let roughness = self.roughness.sample(hit.uv);
-        let theta = self.importance_theta(roughness);
-        let phi: f32 = rng.gen::<f32>() * 2.0 * std::f32::consts::PI;
+    /// Probability of sampling the specular lobe instead of the diffuse one.
+    fn specular_probability(&self, uv: Vec2) -> f32 {
+        0.5 * (1.0 + self.metalness.sample(uv))
+    }
 
-        let x = f32::sin(theta) * f32::sin(phi);
-        let y = f32::cos(theta);
-        let z = f32::sin(theta) * f32::cos(phi);
+    pub fn is_emissive(&self) -> bool {
+        !self.emission.is_black()
+    }
+
+    /// Samples an outgoing direction, returning the bounced ray and its pdf.
+    pub fn bounce(&self, w0: &Vec3, hit: &RayHit, sampler: &mut dyn Sampler) -> (Ray, f32) {
+        let n = hit.normal;
+        let lobe = sampler.get_1d();
+        let u = sampler.get_2d();
+        let direction = if lobe < self.specular_probability(hit.uv) {
+            // Sample a microfacet normal and reflect around it
+            let theta = self.importance_theta(self.roughness.sample(hit.uv), u.x);
+            let phi = u.y * 2.0 * std::f32::consts::PI;
+            let h = glm::normalize(&transform_to_world(&spherical_to_local(theta, phi), &n));
+            2.0 * glm::dot(w0, &h) * h - w0
+        } else {
+            cosine_hemisphere(&n, u)
+        };
+        let direction = glm::normalize(&direction);
+        let pdf = self.pdf(w0, &direction, &n, hit.uv);
+        (hit.spawn(direction), pdf)
+    }
 
-        let direction = glm::normalize(&transform_to_world(&glm::vec3(x, y, z), &n));
-        let h = glm::normalize(&(w0 + direction));
+    /// Probability density of `bounce` choosing direction `wi`.
+    pub fn pdf(&self, w0: &Vec3, wi: &Vec3, n: &Vec3, uv: Vec2) -> f32 {
+        let h = glm::normalize(&(w0 + wi));
+        let ks = self.specular_probability(uv);
+        let w0doth = glm::dot(w0, &h);
+        let specular = if w0doth > 0.0 {
+            let d = normal_distribution(n, &h, self.roughness.sample(uv));
+            d * f32::max(0.0, glm::dot(n, &h)) / (4.0 * w0doth)
+        } else {
+            0.0
+        };
+        let diffuse = f32::max(0.0, glm::dot(n, wi)) * glm::one_over_pi::<f32>();
+        ks * specular + (1.0 - ks) * diffuse
+    }
 
-        let cost = f32::max(0.0, glm::dot(&n, &h));
-        let pdf = normal_distribution(&n, &h, roughness) * cost;
-        let p = pdf / (4.0 * f32::max(0.0, glm::dot(&w0, &h)));
-        (Ray::new(hit.point, direction), p)
+    /// Full scattering function (diffuse and specular) for the given directions.
+    pub fn eval(&self, w0: &Vec3, wi: &Vec3, n: &Vec3, uv: Vec2) -> Vec3 {
+        if glm::dot(n, wi) <= 0.0 || glm::dot(n, w0) <= 0.0 {
+            return glm::zero();
+        }
+        let (specular, ks) = self.brdf(w0, wi, n, uv);
+        let lambert = self.albedo.sample(uv) * glm::one_over_pi::<f32>();
+        let kd = (glm::vec3(1.0, 1.0, 1.0) - ks) * (1.0 - self.metalness.sample(uv));
+        kd.component_mul(&lambert) + specular
     }
 
     /// Return type is (brdf, fresnel)
     pub fn brdf(&self, w0: &Vec3, wi: &Vec3, n: &Vec3, uv: Vec2) -> (Vec3, Vec3) {
         let h = glm::normalize(&(w0 + wi));
-        let d = normal_distribution(&n, &h, self.roughness.sample(uv));
+        let d = normal_distribution(n, &h, self.roughness.sample(uv));
         let f0 = glm::vec3(0.04, 0.04, 0.04);
         let f0 = glm::mix(&f0, &self.albedo.sample(uv), self.metalness.sample(uv));
-        let f = fresnel(&wi, &h, &f0);
-        let g = geometry(&n, &h, w0, wi);
+        let f = fresnel(wi, &h, &f0);
+        let g = geometry(n, &h, w0, wi);
         let num = d * f * g;
-        let denom = 4.0 * glm::dot(&n, &wi) * glm::dot(&n, &w0);
+        let denom = 4.0 * glm::dot(n, wi) * glm::dot(n, w0);
         (num / denom, f)
     }
 }
@@ -1,18 +1,14 @@
 use crate::geom::{Triangle, Vertex};
 use crate::{Vec2, Vec3};
 
-use std::fs;
-use std::path::Path;
-
 use nalgebra_glm as glm;
 
-pub fn load<P: AsRef<Path>>(path: P) -> std::io::Result<Vec<Triangle>> {
+pub fn parse(text: &str) -> Vec<Triangle> {
     let mut verts = Vec::new();
     let mut coords = Vec::new();
     let mut norms = Vec::new();
     let mut tris = Vec::new();
 
-    let text = fs::read_to_string(path)?;
     for mut iter in text
         .lines()
         .filter(|line| !line.starts_with('#'))
@@ -36,7 +32,7 @@ pub fn load<P: AsRef<Path>>(path: P) -> std::io::Result<Vec<Triangle>> {
             _ => (),
         }
     }
-    Ok(tris)
+    tris
 }
 
 fn parse_vec3<'a, I: Iterator<Item = &'a str>>(iter: I) -> Option<Vec3> {
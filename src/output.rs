@@ -0,0 +1,344 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use exr::prelude::{AnyChannel, AnyChannels, Encoding, FlatSamples, Layer, LayerAttributes};
+use exr::prelude::{Image, SmallVec, WritableImage as _};
+use image::hdr::HDREncoder;
+use serde::Deserialize;
+
+use crate::config::RenderParams;
+use crate::geom::{Scene, TraceResult, Traceable as _};
+use crate::integrator::Integrator;
+use crate::ray::Ray;
+use crate::texture::Texture as _;
+use crate::tonemap;
+use crate::vec::{glm, Vec2, Vec3};
+
+/// Additional passes to write next to the rendered image.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct OutputConfig {
+    pub depth: bool,
+    pub normal: bool,
+    pub albedo: bool,
+    pub uv: bool,
+    pub object_id: bool,
+    /// Number of samples taken in every pixel, shown as a heatmap
+    pub sample_heatmap: bool,
+    /// Write the image and all enabled passes as channels of a single OpenEXR file
+    pub layered: bool,
+}
+
+/// Values of the arbitrary output passes at a single pixel.
+pub struct Aov {
+    pub depth: f32,
+    pub normal: Vec3,
+    pub albedo: Vec3,
+    pub uv: Vec2,
+    /// Index of the hit object, if any
+    pub object: Option<usize>,
+}
+
+impl Aov {
+    /// Evaluates the passes at the primary hit of `ray`.
+    pub fn at(ray: &Ray, scene: &Scene) -> Self {
+        match scene.trace(ray, 0.0, f32::MAX) {
+            Some(TraceResult { object, hit, index }) => Aov {
+                depth: hit.t,
+                normal: hit.normal.normalize(),
+                albedo: object.material.albedo.sample(hit.uv),
+                uv: hit.uv,
+                object: Some(index),
+            },
+            None => Aov::empty(),
+        }
+    }
+
+    /// Passes of a pixel that does not see anything.
+    pub fn empty() -> Self {
+        Aov {
+            depth: f32::INFINITY,
+            normal: glm::zero(),
+            albedo: glm::zero(),
+            uv: glm::zero(),
+            object: None,
+        }
+    }
+}
+
+impl OutputConfig {
+    pub fn any_pass(&self) -> bool {
+        self.depth || self.normal || self.albedo || self.uv || self.object_id || self.sample_heatmap
+    }
+
+    /// Writes the enabled passes next to `image`, either as separate images
+    /// or, if `layered` is set, together with `color` as one OpenEXR file.
+    pub fn write_passes<'a>(
+        &self,
+        image: &Path,
+        (width, height): (u32, u32),
+        color: &[Vec3],
+        aovs: &[Aov],
+        samples: &[u32],
+        gamma: f32,
+    ) -> Result<(), Box<dyn Error + 'a>> {
+        if self.layered {
+            return self.write_layered(
+                &image.with_extension("aov.exr"),
+                (width, height),
+                color,
+                aovs,
+                samples,
+            );
+        }
+        let save =
+            |pass: &str, linear: Vec<Vec3>, encode: &dyn Fn(&Vec3) -> Vec<u8>, color_type| {
+                let path = pass_path(image, pass);
+                if is_float_format(&path) {
+                    save_linear(&path, (width, height), &linear)
+                } else {
+                    let buf = linear.iter().flat_map(encode).collect::<Vec<_>>();
+                    image::save_buffer(path, &buf, width, height, color_type)?;
+                    Ok(())
+                }
+            };
+        if self.depth {
+            // Normalize by the furthest hit so the pass is viewable as an image
+            let far = aovs
+                .iter()
+                .map(|a| a.depth)
+                .filter(|d| d.is_finite())
+                .fold(0.0, f32::max);
+            let linear = aovs
+                .iter()
+                .map(|a| glm::vec3(a.depth, a.depth, a.depth))
+                .collect();
+            let encode = |d: &Vec3| vec![to_byte(f32::min(d.x / far, 1.0))];
+            save("depth", linear, &encode, image::Gray(8))?;
+        }
+        if self.normal {
+            let linear = aovs.iter().map(|a| a.normal).collect();
+            let encode = |n: &Vec3| rgb_bytes(&((n + glm::vec3(1.0, 1.0, 1.0)) * 0.5), 1.0);
+            save("normal", linear, &encode, image::RGB(8))?;
+        }
+        if self.albedo {
+            let linear = aovs.iter().map(|a| a.albedo).collect();
+            let encode = |albedo: &Vec3| rgb_bytes(albedo, gamma);
+            save("albedo", linear, &encode, image::RGB(8))?;
+        }
+        if self.uv {
+            let linear = aovs
+                .iter()
+                .map(|a| glm::vec3(a.uv.x, a.uv.y, 0.0))
+                .collect();
+            let encode = |uv: &Vec3| rgb_bytes(uv, 1.0);
+            save("uv", linear, &encode, image::RGB(8))?;
+        }
+        if self.object_id {
+            // Store id + 1, so 0 is the background
+            let linear = aovs
+                .iter()
+                .map(|a| {
+                    let id = a.object.map(|i| i + 1).unwrap_or(0) as f32;
+                    glm::vec3(id, id, id)
+                })
+                .collect();
+            // Spread the id across the three channels in 8 bit images
+            let encode = |id: &Vec3| {
+                let id = id.x as u32;
+                vec![id as u8, (id >> 8) as u8, (id >> 16) as u8]
+            };
+            save("object_id", linear, &encode, image::RGB(8))?;
+        }
+        if self.sample_heatmap {
+            let most = samples.iter().cloned().max().unwrap_or(0).max(1) as f32;
+            let linear = samples
+                .iter()
+                .map(|&n| glm::vec3(n as f32, n as f32, n as f32))
+                .collect();
+            let encode = |n: &Vec3| rgb_bytes(&heatmap(n.x / most), 1.0);
+            save("samples", linear, &encode, image::RGB(8))?;
+        }
+        Ok(())
+    }
+
+    fn write_layered<'a>(
+        &self,
+        path: &Path,
+        (width, height): (u32, u32),
+        color: &[Vec3],
+        aovs: &[Aov],
+        samples: &[u32],
+    ) -> Result<(), Box<dyn Error + 'a>> {
+        let vec3_channels = |names: [&str; 3], f: &dyn Fn(usize) -> Vec3| {
+            (0..3)
+                .map(|c| {
+                    let samples = (0..color.len()).map(|i| f(i)[c]).collect();
+                    AnyChannel::new(names[c], FlatSamples::F32(samples))
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut channels = vec3_channels(["R", "G", "B"], &|i| color[i]);
+        if self.depth {
+            let samples = aovs.iter().map(|a| a.depth).collect();
+            channels.push(AnyChannel::new("depth.Z", FlatSamples::F32(samples)));
+        }
+        if self.normal {
+            let names = ["normal.X", "normal.Y", "normal.Z"];
+            channels.extend(vec3_channels(names, &|i| aovs[i].normal));
+        }
+        if self.albedo {
+            let names = ["albedo.R", "albedo.G", "albedo.B"];
+            channels.extend(vec3_channels(names, &|i| aovs[i].albedo));
+        }
+        if self.uv {
+            let u = aovs.iter().map(|a| a.uv.x).collect();
+            let v = aovs.iter().map(|a| a.uv.y).collect();
+            channels.push(AnyChannel::new("uv.U", FlatSamples::F32(u)));
+            channels.push(AnyChannel::new("uv.V", FlatSamples::F32(v)));
+        }
+        if self.object_id {
+            let ids = aovs
+                .iter()
+                .map(|a| a.object.map(|i| i as u32 + 1).unwrap_or(0))
+                .collect();
+            channels.push(AnyChannel::new("object_id.id", FlatSamples::U32(ids)));
+        }
+        if self.sample_heatmap {
+            let counts = samples.to_vec();
+            channels.push(AnyChannel::new("samples.count", FlatSamples::U32(counts)));
+        }
+        let layer = Layer::new(
+            (width as usize, height as usize),
+            LayerAttributes::default(),
+            Encoding::FAST_LOSSLESS,
+            AnyChannels::sort(SmallVec::from_vec(channels)),
+        );
+        Image::from_layer(layer).write().to_file(path)?;
+        Ok(())
+    }
+}
+
+/// Writes the rendered image, tonemapping it unless `path` is a floating point format.
+pub fn save_image<'a>(
+    path: &Path,
+    (width, height): (u32, u32),
+    colors: &[Vec3],
+    params: &RenderParams,
+    integrator: &dyn Integrator,
+) -> Result<(), Box<dyn Error + 'a>> {
+    if is_float_format(path) {
+        return save_linear(path, (width, height), colors);
+    }
+    let (channels, color_type) = if integrator.grayscale() {
+        (1, image::Gray(8))
+    } else {
+        (3, image::RGB(8))
+    };
+    let buffer = colors
+        .iter()
+        .flat_map(|color| {
+            let pixel = if integrator.tonemapped() {
+                let color = params
+                    .tonemap
+                    .apply(&(color * params.exposure), params.white_point);
+                tonemap::to_rgb8(&color, params.transfer, params.gamma).to_vec()
+            } else {
+                // Data rather than radiance, stored as is like the AOVs
+                rgb_bytes(color, 1.0)
+            };
+            pixel[..channels].to_vec()
+        })
+        .collect::<Vec<_>>();
+    image::save_buffer(path, &buffer, width, height, color_type)?;
+    Ok(())
+}
+
+/// Whether `path` names a format that stores unclamped linear values.
+pub fn is_float_format(path: &Path) -> bool {
+    has_extension(path, "exr") || has_extension(path, "hdr")
+}
+
+fn has_extension(path: &Path, ext: &str) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| e.eq_ignore_ascii_case(ext))
+}
+
+/// Writes linear values without any tonemapping to a Radiance HDR or OpenEXR file.
+pub fn save_linear<'a>(
+    path: &Path,
+    (width, height): (u32, u32),
+    data: &[Vec3],
+) -> Result<(), Box<dyn Error + 'a>> {
+    if has_extension(path, "hdr") {
+        // RGBE cannot represent infinities or NaNs
+        let pixels = data
+            .iter()
+            .map(|c| {
+                let c = c.map(|x| if x.is_finite() { x } else { 0.0 });
+                image::Rgb {
+                    data: [c.x, c.y, c.z],
+                }
+            })
+            .collect::<Vec<_>>();
+        let file = BufWriter::new(File::create(path)?);
+        HDREncoder::new(file).encode(&pixels, width as usize, height as usize)?;
+    } else {
+        let width = width as usize;
+        exr::prelude::write_rgb_file(path, width, height as usize, |x, y| {
+            let c = data[y * width + x];
+            (c.x, c.y, c.z)
+        })?;
+    }
+    Ok(())
+}
+
+/// Path of the image for `pass`, e.g. `out.depth.png` for `out.png`.
+fn pass_path(image: &Path, pass: &str) -> PathBuf {
+    let ext = image.extension().and_then(|e| e.to_str()).unwrap_or("png");
+    image.with_extension(format!("{}.{}", pass, ext))
+}
+
+/// Maps `t` in [0, 1] from blue over green to red.
+fn heatmap(t: f32) -> Vec3 {
+    let t = t.clamp(0.0, 1.0);
+    glm::vec3(
+        (2.0 * t - 1.0).clamp(0.0, 1.0),
+        1.0 - (2.0 * t - 1.0).abs(),
+        (1.0 - 2.0 * t).clamp(0.0, 1.0),
+    )
+}
+
+fn to_byte(x: f32) -> u8 {
+    (x.clamp(0.0, 1.0) * 255.99) as u8
+}
+
+fn rgb_bytes(color: &Vec3, gamma: f32) -> Vec<u8> {
+    color
+        .iter()
+        .map(|c| to_byte(c.clamp(0.0, 1.0).powf(1.0 / gamma)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::integrator::{Debug, DebugMode};
+
+    #[test]
+    fn debug_output_is_encoded_linearly() {
+        let path = std::env::temp_dir().join("prayer_debug_output_test.png");
+        let integrator = Debug {
+            mode: DebugMode::Normals,
+        };
+        let colors = [glm::vec3(0.5, 0.25, 1.0)];
+        let params = RenderParams::default();
+        save_image(&path, (1, 1), &colors, &params, &integrator).unwrap();
+        let pixel = image::open(&path).unwrap().to_rgb().get_pixel(0, 0).data;
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(pixel, [127, 63, 255]);
+    }
+}
@@ -2,10 +2,13 @@ use nalgebra_glm as glm;
 
 type Vec3 = glm::TVec3<f32>;
 
+#[derive(Clone)]
 pub struct Ray {
     pub origin: Vec3,
     pub direction: Vec3,
     pub inv_dir: Vec3,
+    /// Point in the frame at which the ray is cast, between 0 and 1
+    pub time: f32,
 }
 
 impl Ray {
@@ -15,9 +18,14 @@ impl Ray {
             origin,
             direction,
             inv_dir,
+            time: 0.0,
         }
     }
 
+    pub fn with_time(self, time: f32) -> Self {
+        Ray { time, ..self }
+    }
+
     pub fn point_at(&self, t: f32) -> Vec3 {
         self.origin + t * self.direction
     }
@@ -0,0 +1,50 @@
+use rand::{Error, RngCore};
+
+/// Counter-based random number generator.
+///
+/// Every value is a hash of the stream key and its position in the stream, so the numbers
+/// drawn for a pixel sample only depend on the seed, the pixel and the sample index,
+/// and not on which thread happens to render it.
+pub struct CounterRng {
+    key: u64,
+    counter: u64,
+}
+
+impl CounterRng {
+    pub fn new(seed: u64, pixel: u64, sample: u64) -> Self {
+        let key = mix(seed ^ mix(pixel ^ mix(sample)));
+        CounterRng { key, counter: 0 }
+    }
+}
+
+/// SplitMix64 finalizer.
+pub fn mix(mut z: u64) -> u64 {
+    z = z.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+impl RngCore for CounterRng {
+    fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let value = mix(self.key ^ mix(self.counter));
+        self.counter += 1;
+        value
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
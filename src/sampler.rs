@@ -0,0 +1,165 @@
+mod halton;
+mod independent;
+mod sobol;
+mod stratified;
+
+use serde::Deserialize;
+
+pub use self::halton::*;
+pub use self::independent::*;
+pub use self::sobol::*;
+pub use self::stratified::*;
+
+use crate::vec::Vec2;
+
+/// Source of sample values for a single pixel sample.
+///
+/// Every call to `get_1d` or `get_2d` consumes the next dimension(s) of the sample,
+/// so values requested in the same order line up across the samples of a pixel.
+pub trait Sampler {
+    fn get_1d(&mut self) -> f32;
+
+    fn get_2d(&mut self) -> Vec2;
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum SamplerType {
+    Independent,
+    Stratified,
+    Halton,
+    Sobol,
+}
+
+impl SamplerType {
+    /// Sampler for sample `index` out of `count` of the pixel at `pixel`.
+    pub fn start(self, seed: u64, pixel: (u32, u32), index: u32, count: usize) -> PixelSampler {
+        match self {
+            SamplerType::Independent => {
+                PixelSampler::Independent(Independent::new(seed, pixel, index))
+            }
+            SamplerType::Stratified => {
+                PixelSampler::Stratified(Stratified::new(seed, pixel, index, count))
+            }
+            SamplerType::Halton => PixelSampler::Halton(Halton::new(seed, pixel, index)),
+            SamplerType::Sobol => PixelSampler::Sobol(Sobol::new(seed, pixel, index)),
+        }
+    }
+}
+
+pub enum PixelSampler {
+    Independent(Independent),
+    Stratified(Stratified),
+    Halton(Halton),
+    Sobol(Sobol),
+}
+
+impl Sampler for PixelSampler {
+    fn get_1d(&mut self) -> f32 {
+        match self {
+            PixelSampler::Independent(s) => s.get_1d(),
+            PixelSampler::Stratified(s) => s.get_1d(),
+            PixelSampler::Halton(s) => s.get_1d(),
+            PixelSampler::Sobol(s) => s.get_1d(),
+        }
+    }
+
+    fn get_2d(&mut self) -> Vec2 {
+        match self {
+            PixelSampler::Independent(s) => s.get_2d(),
+            PixelSampler::Stratified(s) => s.get_2d(),
+            PixelSampler::Halton(s) => s.get_2d(),
+            PixelSampler::Sobol(s) => s.get_2d(),
+        }
+    }
+}
+
+/// Hashes the sampler seed, pixel and dimension into a 32 bit scrambling seed.
+fn hash(seed: u64, (x, y): (u32, u32), dimension: u32) -> u32 {
+    let key = (u64::from(x) << 32 | u64::from(y)) ^ u64::from(dimension).rotate_left(17);
+    (crate::rng::mix(seed ^ crate::rng::mix(key)) >> 32) as u32
+}
+
+/// Element `i` of a pseudo-random permutation of `0..len` selected by `seed` (Kensler 2013).
+fn permutation_element(mut i: u32, len: u32, seed: u32) -> u32 {
+    let mut w = len - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= seed;
+        i = i.wrapping_mul(0xe170_893d);
+        i ^= seed >> 16;
+        i ^= (i & w) >> 4;
+        i ^= seed >> 8;
+        i = i.wrapping_mul(0x0929_eb3f);
+        i ^= seed >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | seed >> 27);
+        i = i.wrapping_mul(0x6935_fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dc_b303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e50_1cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860_a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < len {
+            break;
+        }
+    }
+    (i.wrapping_add(seed)) % len
+}
+
+/// Maps the high bits of `x` to [0, 1).
+fn to_unit(x: u32) -> f32 {
+    (x >> 8) as f32 * (1.0 / (1u32 << 24) as f32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Index of the interval out of `strata` equal ones in [0, 1) that `x` falls into.
+    pub fn stratum(x: f32, strata: u32) -> u32 {
+        assert!((0.0..1.0).contains(&x));
+        (x * strata as f32) as u32
+    }
+
+    /// Whether no two of `cells` are the same.
+    pub fn distinct(mut cells: Vec<u32>) -> bool {
+        let len = cells.len();
+        cells.sort_unstable();
+        cells.dedup();
+        cells.len() == len
+    }
+
+    #[test]
+    fn permutation_element_is_a_permutation() {
+        for &len in &[1, 2, 3, 7, 16, 100] {
+            for &seed in &[0, 1, 0xdead_beef] {
+                let mut elements = (0..len)
+                    .map(|i| permutation_element(i, len, seed))
+                    .collect::<Vec<_>>();
+                elements.sort_unstable();
+                assert_eq!(elements, (0..len).collect::<Vec<_>>());
+            }
+        }
+    }
+
+    #[test]
+    fn permutation_depends_on_seed() {
+        let permute = |seed| (0..16).map(move |i| permutation_element(i, 16, seed));
+        assert!(!permute(1).eq(permute(2)));
+    }
+
+    #[test]
+    fn to_unit_stays_below_one() {
+        assert_eq!(to_unit(0), 0.0);
+        assert!(to_unit(u32::MAX) < 1.0);
+        assert_eq!(to_unit(1 << 31), 0.5);
+    }
+}
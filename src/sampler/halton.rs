@@ -0,0 +1,102 @@
+use rand::Rng as _;
+
+use super::*;
+use crate::rng::CounterRng;
+use crate::vec::glm;
+
+const PRIMES: [u32; 32] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131,
+];
+
+/// Halton sequence over the samples of a pixel, with the digits of every dimension
+/// Owen-scrambled per pixel so that high bases still cover the whole interval.
+/// Dimensions beyond the tabulated primes fall back to independent random values.
+pub struct Halton {
+    seed: u64,
+    pixel: (u32, u32),
+    index: u32,
+    dimension: u32,
+    fallback: CounterRng,
+}
+
+impl Halton {
+    pub fn new(seed: u64, pixel: (u32, u32), index: u32) -> Self {
+        let key = u64::from(pixel.0) << 32 | u64::from(pixel.1);
+        Halton {
+            seed,
+            pixel,
+            index,
+            dimension: 0,
+            fallback: CounterRng::new(seed, key, u64::from(index)),
+        }
+    }
+}
+
+impl Sampler for Halton {
+    fn get_1d(&mut self) -> f32 {
+        let dimension = self.dimension;
+        self.dimension += 1;
+        match PRIMES.get(dimension as usize) {
+            Some(&base) => {
+                let seed = hash(self.seed, self.pixel, dimension);
+                scrambled_radical_inverse(base, self.index, seed)
+            }
+            None => self.fallback.gen(),
+        }
+    }
+
+    fn get_2d(&mut self) -> Vec2 {
+        glm::vec2(self.get_1d(), self.get_1d())
+    }
+}
+
+/// Mirrors the digits of `index` in `base` around the radix point, permuting every digit
+/// based on the digits before it. Continues past the last nonzero digit, so the
+/// leading zeros get scrambled too.
+fn scrambled_radical_inverse(base: u32, mut index: u32, seed: u32) -> f32 {
+    let inv_base = 1.0 / base as f32;
+    let mut inv_base_m = 1.0;
+    let mut reversed: u64 = 0;
+    while 1.0 - (base - 1) as f32 * inv_base_m < 1.0 {
+        let digit = index % base;
+        index /= base;
+        let digit_seed = (crate::rng::mix(u64::from(seed) ^ reversed) >> 32) as u32;
+        let digit = permutation_element(digit, base, digit_seed);
+        reversed = reversed * u64::from(base) + u64::from(digit);
+        inv_base_m *= inv_base;
+    }
+    f32::min(reversed as f32 * inv_base_m, 1.0 - f32::EPSILON)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{distinct, stratum};
+    use super::*;
+
+    #[test]
+    fn radical_inverse_is_stratified_in_its_base() {
+        for &(base, count) in &[(2, 16), (3, 27), (5, 25)] {
+            for &seed in &[0, 0x9e37_79b9] {
+                let xs = (0..count)
+                    .map(|i| stratum(scrambled_radical_inverse(base, i, seed), count))
+                    .collect();
+                assert!(distinct(xs));
+            }
+        }
+    }
+
+    #[test]
+    fn dimensions_are_scrambled_per_pixel() {
+        let x = |pixel| Halton::new(0, pixel, 1).get_1d();
+        assert_ne!(x((0, 0)), x((1, 0)));
+    }
+
+    #[test]
+    fn falls_back_to_random_values_past_the_primes() {
+        let mut sampler = Halton::new(0, (0, 0), 3);
+        for _ in 0..PRIMES.len() + 8 {
+            assert!((0.0..1.0).contains(&sampler.get_1d()));
+        }
+    }
+}
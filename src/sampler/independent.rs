@@ -0,0 +1,29 @@
+use rand::Rng as _;
+
+use super::*;
+use crate::rng::CounterRng;
+use crate::vec::glm;
+
+/// Uniform random values with no correlation between samples.
+pub struct Independent {
+    rng: CounterRng,
+}
+
+impl Independent {
+    pub fn new(seed: u64, (x, y): (u32, u32), index: u32) -> Self {
+        let pixel = u64::from(x) << 32 | u64::from(y);
+        Independent {
+            rng: CounterRng::new(seed, pixel, u64::from(index)),
+        }
+    }
+}
+
+impl Sampler for Independent {
+    fn get_1d(&mut self) -> f32 {
+        self.rng.gen()
+    }
+
+    fn get_2d(&mut self) -> Vec2 {
+        glm::vec2(self.rng.gen(), self.rng.gen())
+    }
+}
@@ -0,0 +1,130 @@
+use super::*;
+use crate::vec::glm;
+
+/// Owen-scrambled Sobol points, following Burley's "Practical Hash-based Owen Scrambling".
+///
+/// Every 1D or 2D request uses the first one or two Sobol dimensions with their own
+/// scrambling seeds, and the sample index is shuffled per request to decorrelate them.
+pub struct Sobol {
+    seed: u64,
+    pixel: (u32, u32),
+    index: u32,
+    dimension: u32,
+}
+
+impl Sobol {
+    pub fn new(seed: u64, pixel: (u32, u32), index: u32) -> Self {
+        Sobol {
+            seed,
+            pixel,
+            index,
+            dimension: 0,
+        }
+    }
+
+    /// Index of this sample, shuffled for the next request, along with that request's seed.
+    fn next_request(&mut self) -> (u32, u32) {
+        let seed = hash(self.seed, self.pixel, self.dimension);
+        self.dimension += 1;
+        (nested_uniform_scramble(self.index, seed), seed)
+    }
+}
+
+impl Sampler for Sobol {
+    fn get_1d(&mut self) -> f32 {
+        let (index, seed) = self.next_request();
+        let x = nested_uniform_scramble(sobol(index, 0), mix_seed(seed, 0));
+        to_unit(x)
+    }
+
+    fn get_2d(&mut self) -> Vec2 {
+        let (index, seed) = self.next_request();
+        let x = nested_uniform_scramble(sobol(index, 0), mix_seed(seed, 0));
+        let y = nested_uniform_scramble(sobol(index, 1), mix_seed(seed, 1));
+        glm::vec2(to_unit(x), to_unit(y))
+    }
+}
+
+/// Unscrambled point `index` of the first (`dimension` 0) or second Sobol dimension.
+fn sobol(index: u32, dimension: u32) -> u32 {
+    let mut result = 0;
+    let mut direction = 1 << 31;
+    let mut index = index;
+    while index != 0 {
+        if index & 1 != 0 {
+            result ^= direction;
+        }
+        index >>= 1;
+        direction = if dimension == 0 {
+            direction >> 1
+        } else {
+            direction ^ (direction >> 1)
+        };
+    }
+    result
+}
+
+fn mix_seed(seed: u32, dimension: u32) -> u32 {
+    let mut x = seed ^ dimension.wrapping_mul(0x9e37_79b9);
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb_352d);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846c_a68b);
+    x ^ (x >> 16)
+}
+
+fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50_b47c);
+    x ^= x.wrapping_mul(0xb82f_1e52);
+    x ^= x.wrapping_mul(0xc7af_e638);
+    x ^= x.wrapping_mul(0x8d22_f6e6);
+    x
+}
+
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{distinct, stratum};
+    use super::*;
+
+    #[test]
+    fn unscrambled_points_match_the_sequence() {
+        let first = (0..4).map(|i| sobol(i, 0)).collect::<Vec<_>>();
+        let second = (0..4).map(|i| sobol(i, 1)).collect::<Vec<_>>();
+        assert_eq!(first, [0, 0x8000_0000, 0x4000_0000, 0xc000_0000]);
+        assert_eq!(second, [0, 0x8000_0000, 0xc000_0000, 0x4000_0000]);
+    }
+
+    #[test]
+    fn scrambling_is_a_permutation() {
+        let mut scrambled = (0..256)
+            .map(|i| nested_uniform_scramble(i << 24, 0x1234_5678) >> 24)
+            .collect::<Vec<_>>();
+        scrambled.sort_unstable();
+        assert_eq!(scrambled, (0..256).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn pixel_samples_are_stratified() {
+        let samplers = || (0..16).map(|i| Sobol::new(7, (3, 5), i));
+        let xs = samplers().map(|mut s| stratum(s.get_1d(), 16)).collect();
+        assert!(distinct(xs));
+        // Every 4x4 grid cell gets exactly one of the 2D points, in every dimension
+        for dimension in 0..3 {
+            let cells = samplers()
+                .map(|mut s| {
+                    for _ in 0..dimension {
+                        s.get_2d();
+                    }
+                    let p = s.get_2d();
+                    stratum(p.x, 4) * 4 + stratum(p.y, 4)
+                })
+                .collect();
+            assert!(distinct(cells));
+        }
+    }
+}
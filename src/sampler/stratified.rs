@@ -0,0 +1,93 @@
+use rand::Rng as _;
+
+use super::*;
+use crate::rng::CounterRng;
+use crate::vec::glm;
+
+/// Splits every dimension into one stratum per sample and jitters the samples inside them.
+/// Strata are assigned through a different permutation for every dimension,
+/// so that dimensions are not correlated with each other.
+pub struct Stratified {
+    seed: u64,
+    pixel: (u32, u32),
+    index: u32,
+    count: u32,
+    dimension: u32,
+    jitter: CounterRng,
+}
+
+impl Stratified {
+    pub fn new(seed: u64, pixel: (u32, u32), index: u32, count: usize) -> Self {
+        let key = u64::from(pixel.0) << 32 | u64::from(pixel.1);
+        Stratified {
+            seed,
+            pixel,
+            index,
+            count: u32::max(count as u32, 1),
+            dimension: 0,
+            jitter: CounterRng::new(seed, key, u64::from(index)),
+        }
+    }
+
+    /// Stratum of this sample in the next dimension, out of `strata`.
+    fn stratum(&mut self, strata: u32) -> u32 {
+        let seed = hash(self.seed, self.pixel, self.dimension);
+        self.dimension += 1;
+        permutation_element(self.index % strata, strata, seed)
+    }
+}
+
+impl Sampler for Stratified {
+    fn get_1d(&mut self) -> f32 {
+        let stratum = self.stratum(self.count);
+        let jitter: f32 = self.jitter.gen();
+        f32::min(
+            (stratum as f32 + jitter) / self.count as f32,
+            1.0 - f32::EPSILON,
+        )
+    }
+
+    fn get_2d(&mut self) -> Vec2 {
+        // Use the most square grid with at least one cell per sample
+        let nx = f32::ceil(f32::sqrt(self.count as f32)) as u32;
+        let ny = self.count.div_ceil(nx);
+        let stratum = self.stratum(nx * ny);
+        self.dimension += 1;
+        let (sx, sy) = (stratum % nx, stratum / nx);
+        let x = (sx as f32 + self.jitter.gen::<f32>()) / nx as f32;
+        let y = (sy as f32 + self.jitter.gen::<f32>()) / ny as f32;
+        glm::vec2(
+            f32::min(x, 1.0 - f32::EPSILON),
+            f32::min(y, 1.0 - f32::EPSILON),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{distinct, stratum};
+    use super::*;
+
+    #[test]
+    fn pixel_samples_are_stratified() {
+        let samplers = || (0..16).map(|i| Stratified::new(1, (2, 3), i, 16));
+        for dimension in 0..3 {
+            let xs = samplers()
+                .map(|mut s| {
+                    for _ in 0..dimension {
+                        s.get_1d();
+                    }
+                    stratum(s.get_1d(), 16)
+                })
+                .collect();
+            assert!(distinct(xs));
+        }
+        let cells = samplers()
+            .map(|mut s| {
+                let p = s.get_2d();
+                stratum(p.x, 4) * 4 + stratum(p.y, 4)
+            })
+            .collect();
+        assert!(distinct(cells));
+    }
+}
@@ -26,6 +26,10 @@ impl ColorTexture {
             height: 1,
         }
     }
+
+    pub fn is_black(&self) -> bool {
+        self.buf.iter().all(|c| *c == glm::zero::<Vec3>())
+    }
 }
 
 impl Default for ColorTexture {
@@ -79,7 +83,7 @@ fn rgb_to_float(pix: image::Rgb<u8>) -> Vec3 {
     let vec = Vec3::new(
         f32::from(r) / 255.0,
         f32::from(g) / 255.0,
-        f32::from(b) as f32 / 255.0,
+        f32::from(b) / 255.0,
     );
     glm::pow(&vec, &glm::vec3(2.2, 2.2, 2.2))
 }
@@ -0,0 +1,125 @@
+use serde::Deserialize;
+
+use crate::vec::Vec3;
+
+/// Curve used to compress linear radiance into displayable [0, 1] values.
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Tonemap {
+    /// `1 - exp(-color)`
+    Exponential,
+    /// `color / (1 + color)`
+    Reinhard,
+    /// Reinhard, reaching white exactly at the white point
+    ReinhardExtended,
+    /// Narkowicz's fit of the ACES filmic curve
+    Aces,
+    /// Clamp to [0, 1] without any compression
+    Linear,
+}
+
+/// Encoding applied to tonemapped values before quantization.
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Transfer {
+    /// Plain `x^(1/gamma)` power curve
+    Gamma,
+    /// Piecewise sRGB transfer function
+    Srgb,
+}
+
+impl Tonemap {
+    pub fn apply(self, color: &Vec3, white_point: f32) -> Vec3 {
+        let curve = |x: f32| match self {
+            Tonemap::Exponential => 1.0 - f32::exp(-x),
+            Tonemap::Reinhard => x / (1.0 + x),
+            Tonemap::ReinhardExtended => x * (1.0 + x / (white_point * white_point)) / (1.0 + x),
+            Tonemap::Aces => (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14),
+            Tonemap::Linear => x,
+        };
+        color.map(|x| curve(f32::max(x, 0.0)))
+    }
+}
+
+impl Transfer {
+    pub fn encode(self, x: f32, gamma: f32) -> f32 {
+        let x = x.clamp(0.0, 1.0);
+        match self {
+            Transfer::Gamma => x.powf(1.0 / gamma),
+            Transfer::Srgb if x <= 0.003_130_8 => 12.92 * x,
+            Transfer::Srgb => 1.055 * x.powf(1.0 / 2.4) - 0.055,
+        }
+    }
+}
+
+/// Converts linear radiance to 8 bit display values.
+pub fn to_rgb8(color: &Vec3, transfer: Transfer, gamma: f32) -> [u8; 3] {
+    let encode = |x: f32| (transfer.encode(x, gamma) * 255.99) as u8;
+    [encode(color.x), encode(color.y), encode(color.z)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vec::glm;
+
+    const ALL: [Tonemap; 5] = [
+        Tonemap::Exponential,
+        Tonemap::Reinhard,
+        Tonemap::ReinhardExtended,
+        Tonemap::Aces,
+        Tonemap::Linear,
+    ];
+
+    fn apply(tonemap: Tonemap, x: f32) -> f32 {
+        tonemap.apply(&glm::vec3(x, x, x), 4.0).x
+    }
+
+    #[test]
+    fn black_stays_black() {
+        for &tonemap in &ALL {
+            assert_eq!(apply(tonemap, 0.0), 0.0);
+            assert_eq!(apply(tonemap, -1.0), 0.0);
+        }
+    }
+
+    #[test]
+    fn curves_are_monotonic() {
+        for &tonemap in &ALL {
+            let values = (0..100).map(|i| apply(tonemap, i as f32 * 0.05));
+            let values = values.collect::<Vec<_>>();
+            assert!(values.windows(2).all(|w| w[0] < w[1]));
+        }
+    }
+
+    #[test]
+    fn compressing_curves_stay_below_white() {
+        // The ACES fit overshoots slightly and relies on the transfer's clamp instead
+        for &tonemap in &[Tonemap::Exponential, Tonemap::Reinhard] {
+            assert!(apply(tonemap, 1000.0) <= 1.0);
+        }
+        assert!((apply(Tonemap::Reinhard, 1.0) - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn reinhard_extended_reaches_white_at_white_point() {
+        assert!((apply(Tonemap::ReinhardExtended, 4.0) - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn transfers_map_unit_range_to_itself() {
+        for &transfer in &[Transfer::Gamma, Transfer::Srgb] {
+            assert_eq!(transfer.encode(0.0, 2.2), 0.0);
+            assert!((transfer.encode(1.0, 2.2) - 1.0).abs() < 1e-6);
+            assert!((transfer.encode(2.0, 2.2) - 1.0).abs() < 1e-6);
+        }
+        assert!((Transfer::Gamma.encode(0.25, 2.0) - 0.5).abs() < 1e-6);
+        assert!((Transfer::Srgb.encode(0.001, 2.2) - 0.01292).abs() < 1e-6);
+    }
+
+    #[test]
+    fn to_rgb8_clamps_and_quantizes() {
+        let pixel = to_rgb8(&glm::vec3(0.0, 0.25, 5.0), Transfer::Gamma, 2.0);
+        assert_eq!(pixel, [0, 127, 255]);
+    }
+}
@@ -1,6 +1,40 @@
 pub use glm::{Vec2, Vec3};
 pub use nalgebra_glm as glm;
 
+pub fn transform_to_world(vec: &Vec3, norm: &Vec3) -> Vec3 {
+    // Find an axis that is not parallel to normal
+    let major_axis = if f32::abs(norm.x) < (1.0 / f32::sqrt(3.0)) {
+        glm::vec3(1.0, 0.0, 0.0)
+    } else if f32::abs(norm.y) < (1.0 / f32::sqrt(3.0)) {
+        glm::vec3(0.0, 1.0, 0.0)
+    } else {
+        glm::vec3(0.0, 0.0, 1.0)
+    };
+
+    // Create a coordinate system relative to world space
+    let u = glm::normalize(&norm.cross(&major_axis));
+    let v = norm.cross(&u);
+    let w = norm;
+
+    // Transform from local coordinates to world coordinates
+    v * vec.x + w * vec.y + u * vec.z
+}
+
+/// Direction in local coordinates (y up) from spherical angles.
+pub fn spherical_to_local(theta: f32, phi: f32) -> Vec3 {
+    let x = f32::sin(theta) * f32::sin(phi);
+    let y = f32::cos(theta);
+    let z = f32::sin(theta) * f32::cos(phi);
+    glm::vec3(x, y, z)
+}
+
+/// Cosine-weighted direction in the hemisphere around `norm`.
+pub fn cosine_hemisphere(norm: &Vec3, u: Vec2) -> Vec3 {
+    let theta = f32::asin(f32::sqrt(u.x));
+    let phi = u.y * glm::two_pi::<f32>();
+    transform_to_world(&spherical_to_local(theta, phi), norm)
+}
+
 /*
 pub fn component_minmax(a: &Vec3, b: &Vec3) -> (Vec3, Vec3) {
     let (minx, maxx) = if a.x < b.x { (a.x, b.x) } else { (b.x, a.x) };